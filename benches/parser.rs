@@ -0,0 +1,144 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use racketchain_server::messages::{Block, BlockRef, MessageRef, Transaction, TransactionRef};
+
+const KEY: &str = concat!(
+    "AAAAB3NzaC1yc2EAAAADAQABAAAAQQDbXz4rfbrRrXYQJbwuC",
+    "kIyIsccHRpxhxqxgKeneVF4eUXof6e2nLvdXkGA0Y6uBAQ6N7qKxasVTR/2s1N2OBWF"
+);
+const SIG: &str = concat!(
+    "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA",
+    "AAAAAAAAAAAAAA=="
+);
+
+/// The split/collect parser the borrowed types replaced, kept as a baseline.
+mod legacy {
+    #[allow(dead_code)]
+    pub struct Transaction {
+        pub serial: u64,
+        pub unique_string: String,
+        pub sig: String,
+        pub sender: String,
+        pub moves: Vec<(String, f64)>,
+    }
+
+    pub fn parse_transaction(s: &str) -> Result<Transaction, String> {
+        let split = s.split(':').collect::<Vec<&str>>();
+        if split.len() < 5 {
+            return Err("Transaction has less than five parts".to_string());
+        }
+        let serial = split[0]
+            .parse::<u64>()
+            .map_err(|_| "Serial is not a number".to_string())?;
+        let unique_string = split[2].to_string();
+        base64::decode(&unique_string).map_err(|_| "Unique string is not base64".to_string())?;
+        let sig = split[3].to_string();
+        base64::decode(&sig).map_err(|_| "Signature is not base64".to_string())?;
+        let sender = split[4].to_string();
+        base64::decode(&sender).map_err(|_| "Sender is not base64".to_string())?;
+        let moves = split[5..]
+            .iter()
+            .map(|m| {
+                let parts = m.split(',').collect::<Vec<&str>>();
+                let from = parts[0].to_string();
+                base64::decode(&from).map_err(|_| "Recipient is not base64".to_string())?;
+                let amount = parts[1]
+                    .parse::<f64>()
+                    .map_err(|_| "Amount is not a number".to_string())?;
+                Ok((from, amount))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Transaction {
+            serial,
+            unique_string,
+            sig,
+            sender,
+            moves,
+        })
+    }
+
+    pub fn parse_block(s: &str) -> Result<Vec<Transaction>, String> {
+        let split = s.split(':').collect::<Vec<&str>>();
+        split[4..]
+            .iter()
+            .map(|x| parse_transaction(&x.replace(';', ":")))
+            .collect()
+    }
+}
+
+fn transaction(serial: u64, sep: char) -> String {
+    let moves = (0..3)
+        .map(|i| format!("{},{}.5", KEY, i + 1))
+        .collect::<Vec<_>>()
+        .join(&sep.to_string());
+    [
+        &serial.to_string(),
+        "transaction",
+        "Zm9vYmFy",
+        SIG,
+        KEY,
+        &moves,
+    ]
+    .join(&sep.to_string())
+}
+
+fn block(serial: u64, num_transactions: u64) -> String {
+    let transactions = (0..num_transactions)
+        .map(|i| transaction(i, ';'))
+        .collect::<Vec<_>>()
+        .join(":");
+    format!("{}:block:1337:{}:{}", serial, KEY, transactions)
+}
+
+fn bench_transaction(c: &mut Criterion) {
+    let line = transaction(12, ':');
+    let mut group = c.benchmark_group("transaction");
+    group.bench_function("legacy", |b| {
+        b.iter(|| legacy::parse_transaction(black_box(&line)).unwrap())
+    });
+    group.bench_function("owned", |b| {
+        b.iter(|| black_box(&line).parse::<Transaction>().unwrap())
+    });
+    group.bench_function("borrowed", |b| {
+        b.iter(|| TransactionRef::parse(black_box(&line)).unwrap())
+    });
+    group.finish();
+}
+
+fn bench_block(c: &mut Criterion) {
+    let mut group = c.benchmark_group("block");
+    for size in [10, 100, 1000] {
+        let line = block(size, size);
+        group.bench_with_input(BenchmarkId::new("legacy", size), &line, |b, line| {
+            b.iter(|| legacy::parse_block(black_box(line)).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("owned", size), &line, |b, line| {
+            b.iter(|| black_box(line).parse::<Block>().unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("borrowed", size), &line, |b, line| {
+            b.iter(|| BlockRef::parse(black_box(line)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_replay(c: &mut Criterion) {
+    let log = (0..1000u64)
+        .map(|i| {
+            if i % 10 == 9 {
+                block(i, 9)
+            } else {
+                transaction(i, ':')
+            }
+        })
+        .collect::<Vec<_>>();
+    c.bench_function("replay/borrowed", |b| {
+        b.iter(|| {
+            for line in &log {
+                black_box(MessageRef::parse(line).unwrap());
+            }
+        })
+    });
+}
+
+criterion_group!(benches, bench_transaction, bench_block, bench_replay);
+criterion_main!(benches);
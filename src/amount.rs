@@ -0,0 +1,244 @@
+use std::{fmt::Display, str::FromStr};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// An exact, non-negative decimal number with at most [`Amount::DECIMALS`]
+/// fractional digits, stored as an integer count of the smallest unit.
+///
+/// Amounts are written in their canonical form: no sign, no exponent, no
+/// leading zeros and no trailing fractional zeros, so `2.50` and `2.5` are the
+/// same amount but only `2.5` is ever printed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(u64);
+
+impl Amount {
+    /// The maximum number of digits allowed after the decimal point.
+    pub const DECIMALS: u32 = 8;
+
+    const SCALE: u64 = 10u64.pow(Self::DECIMALS);
+
+    pub const ZERO: Amount = Amount(0);
+    pub const MAX: Amount = Amount(u64::MAX);
+
+    /// Creates an amount from a count of the smallest unit (`10^-DECIMALS`).
+    pub const fn from_units(units: u64) -> Self {
+        Amount(units)
+    }
+
+    /// Creates an amount with no fractional part.
+    ///
+    /// # Panics
+    ///
+    /// If `whole` is more than the whole part of [`Amount::MAX`].
+    pub const fn from_whole(whole: u64) -> Self {
+        assert!(whole <= u64::MAX / Self::SCALE, "Amount is too big");
+        Amount(whole * Self::SCALE)
+    }
+
+    /// Returns the amount as a count of the smallest unit.
+    pub const fn units(self) -> u64 {
+        self.0
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    /// Reads an amount the way legacy clients wrote them, as a float,
+    /// rounding to [`Amount::DECIMALS`] places and capping at
+    /// [`Amount::MAX`]. Only meant for lines already in the log; anything
+    /// new is held to the canonical form.
+    pub fn parse_lenient(s: &str) -> Result<Self, String> {
+        if let Ok(amount) = s.parse() {
+            return Ok(amount);
+        }
+        match s.parse::<f64>() {
+            // the cast saturates, so huge amounts come out as the maximum
+            Ok(x) if x >= 0.0 => Ok(Amount((x * Self::SCALE as f64).round() as u64)),
+            _ => Err("Amount is not a number".to_string()),
+        }
+    }
+}
+
+impl FromStr for Amount {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (whole, frac) = match s.split_once('.') {
+            Some((whole, frac)) => (whole, Some(frac)),
+            None => (s, None),
+        };
+
+        // only plain digits are allowed, so no signs, exponents, NaN or inf
+        let is_digits = |x: &str| !x.is_empty() && x.bytes().all(|b| b.is_ascii_digit());
+        if !is_digits(whole) || frac.is_some_and(|x| !is_digits(x)) {
+            return Err("Amount is not a number".to_string());
+        }
+
+        if whole.len() > 1 && whole.starts_with('0') {
+            return Err("Amount has leading zeros".to_string());
+        }
+
+        let frac = frac.unwrap_or("");
+        if frac.len() > Self::DECIMALS as usize {
+            return Err(format!(
+                "Amount has more than {} decimal places",
+                Self::DECIMALS
+            ));
+        }
+
+        let too_big = || "Amount is too big".to_string();
+        let whole = whole.parse::<u64>().map_err(|_| too_big())?;
+        let frac = if frac.is_empty() {
+            0
+        } else {
+            // the length check above guarantees this fits
+            frac.parse::<u64>().unwrap() * 10u64.pow(Self::DECIMALS - frac.len() as u32)
+        };
+
+        whole
+            .checked_mul(Self::SCALE)
+            .and_then(|x| x.checked_add(frac))
+            .map(Amount)
+            .ok_or_else(too_big)
+    }
+}
+
+impl Display for Amount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let whole = self.0 / Self::SCALE;
+        let frac = self.0 % Self::SCALE;
+        if frac == 0 {
+            return write!(f, "{}", whole);
+        }
+
+        let digits = format!("{:0width$}", frac, width = Self::DECIMALS as usize);
+        write!(f, "{}.{}", whole, digits.trim_end_matches('0'))
+    }
+}
+
+// serialized in the same text form as everywhere else
+impl Serialize for Amount {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        String::deserialize(d)?.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod amount_tests {
+    use super::Amount;
+
+    #[test]
+    fn test_amount_from_str() {
+        assert_eq!("1".parse::<Amount>().unwrap(), Amount::from_whole(1));
+        assert_eq!("1.0".parse::<Amount>().unwrap(), Amount::from_whole(1));
+        assert_eq!(
+            "0.5".parse::<Amount>().unwrap(),
+            Amount::from_units(50_000_000)
+        );
+        assert_eq!(
+            "0.00000001".parse::<Amount>().unwrap(),
+            Amount::from_units(1)
+        );
+        assert_eq!(
+            "184467440737.09551615".parse::<Amount>().unwrap(),
+            Amount::MAX
+        );
+    }
+
+    #[test]
+    fn test_amount_rejects_non_canonical_numbers() {
+        for bad in [
+            "", ".", "1.", ".5", "-1", "+1", "1e5", "1E-3", "NaN", "inf", "Infinity", " 1",
+            "1_000", "0x10", "1.2.3",
+        ] {
+            assert_eq!(
+                bad.parse::<Amount>().err().unwrap(),
+                "Amount is not a number",
+                "{:?}",
+                bad
+            );
+        }
+        assert!("01".parse::<Amount>().is_err());
+        assert!("0.000000001".parse::<Amount>().is_err());
+        assert_eq!(
+            "184467440737.09551616".parse::<Amount>().err().unwrap(),
+            "Amount is too big"
+        );
+        assert!("99999999999999999999".parse::<Amount>().is_err());
+    }
+
+    #[test]
+    fn test_amount_display_is_canonical() {
+        for (input, canonical) in [
+            ("0", "0"),
+            ("2.0", "2"),
+            ("2.50", "2.5"),
+            ("1337", "1337"),
+            ("0.00000001", "0.00000001"),
+            ("10.10000000", "10.1"),
+        ] {
+            let amount = input.parse::<Amount>().unwrap();
+            assert_eq!(amount.to_string(), canonical);
+            assert_eq!(canonical.parse::<Amount>().unwrap(), amount);
+        }
+        assert_eq!(Amount::MAX.to_string(), "184467440737.09551615");
+    }
+
+    #[test]
+    fn test_amount_checked_arithmetic() {
+        let a = Amount::from_whole(3);
+        let b = "1.25".parse::<Amount>().unwrap();
+        assert_eq!(a.checked_sub(b).unwrap().to_string(), "1.75");
+        assert_eq!(a.checked_add(b).unwrap().to_string(), "4.25");
+        assert!(b.checked_sub(a).is_none());
+        assert!(Amount::MAX.checked_add(Amount::from_units(1)).is_none());
+    }
+
+    #[test]
+    fn test_amount_from_whole() {
+        assert_eq!(Amount::from_whole(184467440737).to_string(), "184467440737");
+    }
+
+    #[test]
+    #[should_panic(expected = "Amount is too big")]
+    fn test_amount_from_whole_overflows() {
+        Amount::from_whole(184467440738);
+    }
+
+    #[test]
+    fn test_amount_parse_lenient() {
+        for (legacy, canonical) in [
+            ("2.5", "2.5"),
+            ("2.50", "2.5"),
+            ("0.123456789", "0.12345679"),
+            ("1e2", "100"),
+            ("+3", "3"),
+            ("0.000000001", "0"),
+            (
+                "179769313486231570000000000000000000000000000000000000",
+                "184467440737.09551615",
+            ),
+        ] {
+            let amount = Amount::parse_lenient(legacy).unwrap();
+            assert_eq!(amount.to_string(), canonical, "{:?}", legacy);
+        }
+        for bad in ["", "NaN", "-1", "abc"] {
+            assert!(Amount::parse_lenient(bad).is_err(), "{:?}", bad);
+        }
+    }
+}
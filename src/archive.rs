@@ -0,0 +1,304 @@
+//! Copying the log out of storage and back in.
+//!
+//! Native archives hold one `serial:message` line per message, the same as
+//! the server serves them. JSON Lines and CSV archives hold records with a
+//! serial, the SHA-256 hash of the message as stored, and the message, so a
+//! corrupted archive is caught on import.
+
+use std::{fmt::Display, io, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chain::{Chain, Params},
+    hash::Hash,
+    storage::Storage,
+    verify,
+};
+
+/// How many messages are read or written at a time.
+const BATCH: u64 = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Native,
+    JsonLines,
+    Csv,
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Format::Native => write!(f, "native"),
+            Format::JsonLines => write!(f, "jsonl"),
+            Format::Csv => write!(f, "csv"),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "native" => Ok(Format::Native),
+            "jsonl" => Ok(Format::JsonLines),
+            "csv" => Ok(Format::Csv),
+            _ => Err("Format must be one of native, jsonl or csv".to_string()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    serial: u64,
+    hash: String,
+    message: String,
+}
+
+/// Writes every message in `storage` to `out`, calling `progress` with the
+/// number written so far and the total after every batch.
+pub fn export<W: io::Write>(
+    storage: &mut dyn Storage,
+    out: W,
+    format: Format,
+    progress: &mut dyn FnMut(u64, u64),
+) -> Result<u64, String> {
+    let total = storage.len()?;
+    let mut out = Writer::new(out, format);
+    let mut start = 0;
+    while start < total {
+        let end = (start + BATCH).min(total);
+        for (serial, message) in (start..).zip(storage.range(start, end)?) {
+            out.write(serial, message)?;
+        }
+        start = end;
+        progress(start, total);
+    }
+    out.flush()?;
+    Ok(total)
+}
+
+enum Writer<W: io::Write> {
+    Native(W),
+    JsonLines(W),
+    Csv(Box<csv::Writer<W>>),
+}
+
+impl<W: io::Write> Writer<W> {
+    fn new(out: W, format: Format) -> Self {
+        match format {
+            Format::Native => Writer::Native(out),
+            Format::JsonLines => Writer::JsonLines(out),
+            Format::Csv => Writer::Csv(Box::new(csv::Writer::from_writer(out))),
+        }
+    }
+
+    fn write(&mut self, serial: u64, message: String) -> Result<(), String> {
+        let record = || Record {
+            serial,
+            hash: Hash::of(message.as_bytes()).to_string(),
+            message: message.clone(),
+        };
+        match self {
+            Writer::Native(w) => writeln!(w, "{}:{}", serial, message).map_err(|e| e.to_string()),
+            Writer::JsonLines(w) => {
+                serde_json::to_writer(&mut *w, &record()).map_err(|e| e.to_string())?;
+                writeln!(w).map_err(|e| e.to_string())
+            }
+            Writer::Csv(w) => w.serialize(record()).map_err(|e| e.to_string()),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        match self {
+            Writer::Native(w) | Writer::JsonLines(w) => w.flush(),
+            Writer::Csv(w) => w.flush(),
+        }
+        .map_err(|e| e.to_string())
+    }
+}
+
+/// Reads the messages of an archive along with their serials, checking
+/// each one against its hash where the format has one.
+pub fn read<R: io::Read>(input: R, format: Format) -> Result<Vec<(u64, String)>, String> {
+    let records: Vec<Result<Record, String>> = match format {
+        Format::Native => io::BufRead::lines(io::BufReader::new(input))
+            .map(|line| {
+                let line = line.map_err(|e| e.to_string())?;
+                let (serial, message) = line
+                    .split_once(':')
+                    .ok_or_else(|| "Line has no serial".to_string())?;
+                let serial = serial
+                    .parse()
+                    .map_err(|_| "Serial is not a number".to_string())?;
+                Ok(Record {
+                    serial,
+                    hash: Hash::of(message.as_bytes()).to_string(),
+                    message: message.to_string(),
+                })
+            })
+            .collect(),
+        Format::JsonLines => io::BufRead::lines(io::BufReader::new(input))
+            .map(|line| {
+                let line = line.map_err(|e| e.to_string())?;
+                serde_json::from_str(&line).map_err(|e| e.to_string())
+            })
+            .collect(),
+        Format::Csv => csv::Reader::from_reader(input)
+            .deserialize()
+            .map(|record| record.map_err(|e| e.to_string()))
+            .collect(),
+    };
+
+    let mut messages = Vec::with_capacity(records.len());
+    for (line, record) in (1..).zip(records) {
+        let record = record.map_err(|e| format!("Line {}: {}", line, e))?;
+        if record.hash.parse::<Hash>() != Ok(Hash::of(record.message.as_bytes())) {
+            return Err(format!("Line {}: Hash doesn't match the message", line));
+        }
+        messages.push((record.serial, record.message));
+    }
+    Ok(messages)
+}
+
+/// Appends `messages` to the end of `storage`, calling `progress` with the
+/// number written so far and the total after every batch.
+///
+/// Nothing is written unless every message is valid: serials have to carry
+/// on from the end of the log, and unless `params` is `None`, messages have
+/// to keep to the rules given the ones already stored, like [`verify`] would
+/// check them.
+pub fn import(
+    storage: &mut dyn Storage,
+    messages: Vec<(u64, String)>,
+    params: Option<Params>,
+    progress: &mut dyn FnMut(u64, u64),
+) -> Result<u64, String> {
+    let len = storage.len()?;
+    for ((serial, _), expected) in messages.iter().zip(len..) {
+        if *serial != expected {
+            return Err(format!(
+                "Message {} should have serial {} to follow the log",
+                serial, expected
+            ));
+        }
+    }
+
+    if let Some(params) = params {
+        let stored = storage.range(0, len)?;
+        let (mut chain, _) = verify::verify(params, stored.iter().map(String::as_str));
+        check_rules(&mut chain, &messages)?;
+    }
+
+    let total = messages.len() as u64;
+    let lines = messages.into_iter().map(|(_, m)| m).collect::<Vec<_>>();
+    let mut done = 0;
+    for batch in lines.chunks(BATCH as usize) {
+        storage.append(batch)?;
+        done += batch.len() as u64;
+        progress(done, total);
+    }
+    Ok(total)
+}
+
+fn check_rules(chain: &mut Chain, messages: &[(u64, String)]) -> Result<(), String> {
+    for (serial, line) in messages {
+        let message = verify::check_entry(chain, *serial, line)
+            .map_err(|e| format!("Message {} is invalid: {}", serial, e))?;
+        chain.push(&message, *serial);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod archive_tests {
+    use super::{export, import, read, Format};
+    use crate::{
+        chain::Params,
+        difficulty::DifficultySchedule,
+        messages::NewBlock,
+        storage::Memory,
+        test_util::{KEY, KEY2},
+    };
+
+    fn params() -> Params {
+        Params {
+            difficulty: DifficultySchedule {
+                initial: 0,
+                ..DifficultySchedule::default()
+            },
+            ..Params::default()
+        }
+    }
+
+    fn log() -> Vec<String> {
+        vec![
+            NewBlock::genesis().to_string(),
+            format!("block:1:{}:", KEY2),
+            // even messages that don't parse are copied as they are
+            "garbage, with a comma".to_string(),
+            format!("block:2:{}:", KEY),
+        ]
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for format in [Format::Native, Format::JsonLines, Format::Csv] {
+            let mut source = Memory::new(log());
+            let mut out = Vec::new();
+            let mut seen = Vec::new();
+            let exported = export(&mut source, &mut out, format, &mut |done, total| {
+                seen.push((done, total))
+            })
+            .unwrap();
+            assert_eq!(exported, 4);
+            assert_eq!(seen, vec![(4, 4)]);
+
+            let messages = read(out.as_slice(), format).unwrap();
+            let mut dest = Memory::default();
+            import(&mut dest, messages, None, &mut |_, _| {}).unwrap();
+            assert_eq!(dest.messages, log(), "{}", format);
+        }
+    }
+
+    #[test]
+    fn test_import_validates() {
+        let mut out = Vec::new();
+        export(
+            &mut Memory::new(log()),
+            &mut out,
+            Format::JsonLines,
+            &mut |_, _| {},
+        )
+        .unwrap();
+        let archive = String::from_utf8(out).unwrap();
+
+        let corrupted = archive.replace(&format!("block:1:{}", KEY2), &format!("block:9:{}", KEY2));
+        assert_eq!(
+            read(corrupted.as_bytes(), Format::JsonLines).err().unwrap(),
+            "Line 2: Hash doesn't match the message"
+        );
+
+        // the archive has to carry on from the end of the log
+        let messages = read(archive.as_bytes(), Format::JsonLines).unwrap();
+        let mut dest = Memory::new(vec![NewBlock::genesis().to_string()]);
+        assert_eq!(
+            import(&mut dest, messages.clone(), None, &mut |_, _| {})
+                .err()
+                .unwrap(),
+            "Message 0 should have serial 1 to follow the log"
+        );
+        assert!(import(&mut dest, messages[1..].to_vec(), None, &mut |_, _| {}).is_ok());
+
+        // and keep to the rules unless told otherwise
+        let mut dest = Memory::default();
+        assert_eq!(
+            import(&mut dest, messages.clone(), Some(params()), &mut |_, _| {})
+                .err()
+                .unwrap(),
+            "Message 2 is invalid: Message has less than two parts"
+        );
+        assert!(dest.messages.is_empty());
+    }
+}
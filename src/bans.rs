@@ -0,0 +1,197 @@
+//! Keeping senders and addresses that misbehave from posting.
+//!
+//! Every ban and unban is kept in storage as an entry of the audit log,
+//! saying who made it, when and why, and the ban list is rebuilt from the
+//! audit log when the server starts.
+
+use std::{collections::HashSet, fmt::Display, net::IpAddr, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::keys;
+
+/// What a ban applies to, written as `key:<public key>` or `ip:<address>`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Target {
+    /// Transactions sent and blocks mined by the account.
+    Key(String),
+    /// Posts from the address.
+    Ip(IpAddr),
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Key(key) => write!(f, "key:{}", key),
+            Target::Ip(ip) => write!(f, "ip:{}", ip),
+        }
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("key", key)) => {
+                keys::parse_public_key(key)?;
+                Ok(Target::Key(key.to_string()))
+            }
+            Some(("ip", ip)) => ip
+                .parse()
+                .map(Target::Ip)
+                .map_err(|_| "Address is not an IP address".to_string()),
+            _ => Err("Target must be key:<public key> or ip:<address>".to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Ban,
+    Unban,
+}
+
+/// An entry of the audit log, written as JSON.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    /// When it was made, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// The name of the admin token it was made with.
+    pub by: String,
+    pub action: Action,
+    pub target: Target,
+    pub reason: String,
+}
+
+impl Display for Entry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let json = serde_json::to_string(self).map_err(|_| std::fmt::Error)?;
+        write!(f, "{}", json)
+    }
+}
+
+impl FromStr for Entry {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str(s).map_err(|e| format!("Audit entry is unreadable: {}", e))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Bans {
+    keys: HashSet<String>,
+    ips: HashSet<IpAddr>,
+}
+
+impl Bans {
+    /// Rebuilds the ban list from the audit log, oldest first.
+    pub fn replay<'a>(log: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut bans = Bans::default();
+        for (i, line) in log.into_iter().enumerate() {
+            let applied = line.parse().and_then(|entry| bans.apply(&entry));
+            if let Err(e) = applied {
+                warn!(entry = i, error = %e, "Skipping audit entry");
+            }
+        }
+        bans
+    }
+
+    /// Checks that `entry` changes something before making the change.
+    pub fn apply(&mut self, entry: &Entry) -> Result<(), String> {
+        let changed = match (&entry.target, entry.action) {
+            (Target::Key(key), Action::Ban) => self.keys.insert(key.clone()),
+            (Target::Key(key), Action::Unban) => self.keys.remove(key),
+            (Target::Ip(ip), Action::Ban) => self.ips.insert(*ip),
+            (Target::Ip(ip), Action::Unban) => self.ips.remove(ip),
+        };
+        match (changed, entry.action) {
+            (true, _) => Ok(()),
+            (false, Action::Ban) => Err(format!("{} is already banned", entry.target)),
+            (false, Action::Unban) => Err(format!("{} isn't banned", entry.target)),
+        }
+    }
+
+    pub fn is_banned(&self, target: &Target) -> bool {
+        match target {
+            Target::Key(key) => self.keys.contains(key),
+            Target::Ip(ip) => self.ips.contains(ip),
+        }
+    }
+
+    /// Every banned key and address, in order.
+    pub fn targets(&self) -> Vec<Target> {
+        let mut targets = self
+            .keys
+            .iter()
+            .cloned()
+            .map(Target::Key)
+            .chain(self.ips.iter().copied().map(Target::Ip))
+            .collect::<Vec<_>>();
+        targets.sort_by_key(|t| t.to_string());
+        targets
+    }
+}
+
+#[cfg(test)]
+mod bans_tests {
+    use super::{Action, Bans, Entry, Target};
+    use crate::test_util::KEY;
+
+    fn entry(action: Action, target: &str) -> Entry {
+        Entry {
+            timestamp: 100,
+            by: "alice".to_string(),
+            action,
+            target: target.parse().unwrap(),
+            reason: "flooding".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_target_round_trip() {
+        for target in [
+            format!("key:{}", KEY),
+            "ip:10.0.0.5".to_string(),
+            "ip:::1".to_string(),
+        ] {
+            assert_eq!(target.parse::<Target>().unwrap().to_string(), target);
+        }
+        assert!("key:Zm9v".parse::<Target>().is_err());
+        assert!("ip:10.0.0".parse::<Target>().is_err());
+        assert!("10.0.0.5".parse::<Target>().is_err());
+    }
+
+    #[test]
+    fn test_replays_the_audit_log() {
+        let ban_key = entry(Action::Ban, &format!("key:{}", KEY));
+        let ban_ip = entry(Action::Ban, "ip:10.0.0.5");
+        let unban_ip = entry(Action::Unban, "ip:10.0.0.5");
+        let log = [
+            ban_key.to_string(),
+            ban_ip.to_string(),
+            "garbage".to_string(),
+            unban_ip.to_string(),
+        ];
+        assert_eq!(log[0].parse::<Entry>().unwrap(), ban_key);
+
+        let mut bans = Bans::replay(log.iter().map(String::as_str));
+        assert_eq!(bans.targets(), vec![ban_key.target.clone()]);
+        assert!(bans.is_banned(&ban_key.target));
+        assert!(!bans.is_banned(&ban_ip.target));
+
+        // entries have to change something
+        assert_eq!(
+            bans.apply(&ban_key).err().unwrap(),
+            format!("key:{} is already banned", KEY)
+        );
+        assert_eq!(
+            bans.apply(&unban_ip).err().unwrap(),
+            "ip:10.0.0.5 isn't banned"
+        );
+    }
+}
@@ -0,0 +1,71 @@
+use std::{thread, time::Duration};
+
+use clap::Parser;
+use racketchain_server::{client::Client, keys, miner::Miner};
+
+/// Mines racketchain blocks on top of a server's chain, paying the rewards
+/// and fees to an account.
+#[derive(Parser)]
+struct Cli {
+    /// The server to mine for, e.g. http://127.0.0.1:8080
+    server: String,
+    /// The account to pay, as the public key the wallet prints
+    account: String,
+    /// A PEM file with the certificate authorities to trust over https,
+    /// instead of the usual ones
+    #[arg(long)]
+    ca: Option<String>,
+    /// The number of threads searching for a nonce, by default one per core
+    #[arg(long)]
+    threads: Option<usize>,
+    /// The number of milliseconds between checks on whether the tip has
+    /// moved on
+    #[arg(long, default_value_t = 1000)]
+    poll_interval: u64,
+    /// Stop after mining this many blocks, or never if not given
+    #[arg(long)]
+    blocks: Option<u64>,
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    if let Err(e) = keys::parse_public_key(&cli.account) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+    let client = Client::connect(&cli.server, cli.ca.as_deref()).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    });
+
+    let threads = cli
+        .threads
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
+    let mut miner = Miner::new(
+        client,
+        cli.account,
+        threads,
+        Duration::from_millis(cli.poll_interval),
+    );
+
+    let mut mined = 0;
+    while cli.blocks.is_none_or(|blocks| mined < blocks) {
+        match miner.mine().await {
+            Ok(Some(block)) => {
+                mined += 1;
+                println!(
+                    "Mined {} with {} transactions",
+                    block.hash(),
+                    block.transactions.len()
+                );
+            }
+            Ok(None) => eprintln!("The tip moved on, starting over"),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                // give the server a moment before trying again
+                tokio::time::sleep(Duration::from_millis(cli.poll_interval)).await;
+            }
+        }
+    }
+}
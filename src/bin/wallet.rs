@@ -0,0 +1,112 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+};
+
+use clap::{Parser, Subcommand};
+use racketchain_server::{
+    amount::Amount,
+    client::Client,
+    messages::{Move, NewMessage},
+    wallet::Wallet,
+};
+
+/// Makes keys for racketchain accounts and sends coins from them.
+#[derive(Parser)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Generates a new key, saving it to a file and printing the account it
+    /// signs for
+    Keygen {
+        /// The file to save the key to, which mustn't exist yet
+        key_file: String,
+    },
+    /// Prints the account a key signs for
+    Account { key_file: String },
+    /// Prints a signed transaction without posting it
+    Sign {
+        key_file: String,
+        #[command(flatten)]
+        payment: Payment,
+    },
+    /// Signs a transaction and posts it to a server
+    Send {
+        key_file: String,
+        /// The server to post to, e.g. http://127.0.0.1:8080
+        server: String,
+        /// A PEM file with the certificate authorities to trust over https,
+        /// instead of the usual ones
+        #[arg(long)]
+        ca: Option<String>,
+        #[command(flatten)]
+        payment: Payment,
+    },
+}
+
+#[derive(clap::Args)]
+struct Payment {
+    /// The accounts to pay and how much, each as <ACCOUNT>,<AMOUNT>
+    #[arg(required = true)]
+    moves: Vec<Move>,
+    /// Paid to the miner of the block that includes the transaction
+    #[arg(long, default_value_t = Amount::ZERO)]
+    fee: Amount,
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+
+    let result = match cli.command {
+        Command::Keygen { key_file } => keygen(&key_file),
+        Command::Account { key_file } => load(&key_file).map(|w| println!("{}", w.account())),
+        Command::Sign { key_file, payment } => load(&key_file).map(|wallet| {
+            println!("{}", wallet.transaction(payment.moves, payment.fee));
+        }),
+        Command::Send {
+            key_file,
+            server,
+            ca,
+            payment,
+        } => match (load(&key_file), Client::connect(&server, ca.as_deref())) {
+            (Ok(wallet), Ok(client)) => {
+                let transaction = wallet.transaction(payment.moves, payment.fee);
+                let unique = transaction.unique_string.clone();
+                let message = NewMessage::NewTransaction(transaction);
+                let result = client.post(&message).await;
+                result.map(|_| eprintln!("Sent {}", unique))
+            }
+            (Err(e), _) | (_, Err(e)) => Err(e),
+        },
+    };
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn load(key_file: &str) -> Result<Wallet, String> {
+    let pem = fs::read_to_string(key_file).map_err(|e| format!("{}: {}", key_file, e))?;
+    pem.parse()
+}
+
+fn keygen(key_file: &str) -> Result<(), String> {
+    let wallet = Wallet::generate()?;
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    // the key is as good as the coins it holds
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(key_file)
+        .map_err(|e| format!("{}: {}", key_file, e))?;
+    file.write_all(wallet.to_pem()?.as_bytes())
+        .map_err(|e| e.to_string())?;
+    println!("{}", wallet.account());
+    Ok(())
+}
@@ -0,0 +1,1039 @@
+//! The server's view of the chain of blocks in the message log.
+
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{
+    difficulty::{self, DifficultySchedule, MAX_FUTURE_DRIFT, MEDIAN_TIME_SPAN},
+    hash::Hash,
+    ledger::{Ledger, LedgerUpdate, RewardSchedule},
+    merkle::{self, Step},
+    messages::{NewBlock, NewMessage, NewTransaction},
+    snapshot::Snapshot,
+};
+
+/// What the chain knows about a stored block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockInfo {
+    /// Where the block is in the message log.
+    pub serial: u64,
+    pub hash: Hash,
+    pub prev_hash: Option<Hash>,
+    /// The number of blocks between this one and the root of its branch.
+    pub height: u64,
+    pub timestamp: Option<u64>,
+    /// The number of leading zero bits the block's hash had to have.
+    pub difficulty: u32,
+    /// The work done on this block and every block before it.
+    pub work: u128,
+}
+
+impl Display for BlockInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}:{}",
+            self.serial, self.hash, self.height, self.work
+        )
+    }
+}
+
+/// What a snapshot keeps of a canonical block with transactions, so they
+/// can still be proven to be in it once the rest of the block is forgotten.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ArchivedBlock {
+    pub info: BlockInfo,
+    pub header: String,
+    /// The serial of each of its transactions and its Merkle leaf, in order.
+    pub leaves: Vec<(u64, Hash)>,
+}
+
+/// The rules blocks are held to, fixed when the server starts.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Params {
+    pub reward: RewardSchedule,
+    pub difficulty: DifficultySchedule,
+}
+
+/// How many side branches to keep the ledger of.
+const MAX_BRANCHES: usize = 16;
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Tracks every block in the log as a tree, which branch is canonical, and
+/// the ledger as of its tip.
+///
+/// The log stores messages the way they were posted, so the chain is rebuilt
+/// by replaying it in order, trusting whatever was already accepted. Blocks on
+/// side branches are stored like any other, and the tip moves to whichever
+/// branch has the most cumulative work, keeping the first one seen on a tie.
+/// Blocks from before the chain was linked by hash have no `prev_hash` and are
+/// taken to extend the tip, but a block whose `prev_hash` isn't known is
+/// left out rather than starting a tree of its own.
+///
+/// The ledger is kept as of the canonical tip, and as of the tips of the
+/// side branches that were extended last, so a block on either only has to
+/// be applied on top of the one it extends.
+///
+/// A chain picked up from a [`Snapshot`] only knows the latest blocks of the
+/// canonical branch as of the snapshot, so blocks can't fork off before its
+/// tip anymore. Of the blocks before that, it only knows enough to prove
+/// which transactions are in them.
+pub struct Chain {
+    params: Params,
+    blocks: HashMap<Hash, BlockInfo>,
+    bodies: HashMap<Hash, NewBlock>,
+    tip: Option<Hash>,
+    ledger: Ledger,
+    // the ledgers as of the tips of side branches
+    branches: HashMap<Hash, Ledger>,
+    // every transaction in the log and its hash, by serial
+    transactions: HashMap<u64, (Hash, NewTransaction)>,
+    // the blocks on any branch each transaction is in, by serial
+    containing: HashMap<u64, Vec<Hash>>,
+    // the canonical block each transaction is in as of the snapshot the chain
+    // was picked up from, by serial
+    archive: HashMap<u64, Arc<ArchivedBlock>>,
+    // the hashes of every readable message in the log, so one can't be
+    // appended twice
+    seen: HashSet<Hash>,
+    len: u64,
+    // the tip of the snapshot the chain was picked up from, and its ledger
+    base: Option<(Hash, Ledger)>,
+}
+
+impl Chain {
+    pub fn new(params: Params) -> Self {
+        Chain {
+            params,
+            blocks: HashMap::new(),
+            bodies: HashMap::new(),
+            tip: None,
+            ledger: Ledger::new(),
+            branches: HashMap::new(),
+            transactions: HashMap::new(),
+            containing: HashMap::new(),
+            archive: HashMap::new(),
+            seen: HashSet::new(),
+            len: 0,
+            base: None,
+        }
+    }
+
+    /// Rebuilds the chain from the stored message log, oldest first.
+    pub fn replay<'a>(params: Params, log: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut chain = Chain::new(params);
+        chain.extend(log);
+        chain
+    }
+
+    /// Replays the messages that follow the ones the chain has seen so far.
+    pub fn extend<'a>(&mut self, log: impl IntoIterator<Item = &'a str>) {
+        for line in log {
+            let serial = self.len;
+            match NewMessage::parse_lenient(line) {
+                Ok(message) => self.push(&message, serial),
+                Err(e) => warn!(serial, error = %e, "Skipping unreadable message"),
+            }
+            self.len = serial + 1;
+        }
+    }
+
+    /// Picks the chain up where `snapshot` left off.
+    pub fn from_snapshot(params: Params, snapshot: Snapshot) -> Result<Self, String> {
+        if snapshot.params != params {
+            return Err("Snapshot was taken under different rules".to_string());
+        }
+        if snapshot
+            .tip
+            .is_some_and(|tip| snapshot.blocks.iter().all(|b| b.hash != tip))
+        {
+            return Err("Snapshot is missing its tip".to_string());
+        }
+
+        let mut transactions = HashMap::new();
+        for (serial, line) in snapshot.transactions {
+            let t = line.parse::<NewTransaction>()?;
+            transactions.insert(serial, (t.hash(), t));
+        }
+        let seen = snapshot
+            .seen
+            .ok_or_else(|| "Snapshot is missing the hashes of its messages".to_string())?;
+        let mut archive = HashMap::new();
+        for block in snapshot.archive {
+            let block = Arc::new(block);
+            for (serial, _) in &block.leaves {
+                archive.insert(*serial, block.clone());
+            }
+        }
+        Ok(Chain {
+            params,
+            blocks: snapshot.blocks.into_iter().map(|b| (b.hash, b)).collect(),
+            bodies: HashMap::new(),
+            tip: snapshot.tip,
+            base: snapshot.tip.map(|tip| (tip, snapshot.ledger.clone())),
+            ledger: snapshot.ledger,
+            branches: HashMap::new(),
+            transactions,
+            containing: HashMap::new(),
+            archive,
+            seen: seen.into_iter().collect(),
+            len: snapshot.len,
+        })
+    }
+
+    /// The state of the chain, keeping enough of the canonical branch to
+    /// work out the difficulty and median time of the blocks to come, and to
+    /// prove what's in the blocks before them.
+    pub fn snapshot(&self) -> Snapshot {
+        let keep = (self.params.difficulty.retarget_interval as usize + 1).max(MEDIAN_TIME_SPAN);
+        let mut blocks = self.canonical().take(keep).copied().collect::<Vec<_>>();
+        blocks.reverse();
+
+        let mut transactions = self
+            .mempool()
+            .into_iter()
+            .map(|(serial, t)| (serial, t.to_string()))
+            .collect::<Vec<_>>();
+        transactions.sort_by_key(|(serial, _)| *serial);
+
+        let mut archive = self
+            .archive
+            .values()
+            .map(|block| (block.info.hash, block.as_ref().clone()))
+            .collect::<HashMap<_, _>>()
+            .into_values()
+            .collect::<Vec<_>>();
+        for info in self.canonical() {
+            let block = match self.bodies.get(&info.hash) {
+                Some(block) if block.merkle_root.is_some() && !block.transactions.is_empty() => {
+                    block
+                }
+                _ => continue,
+            };
+            archive.push(ArchivedBlock {
+                info: *info,
+                header: block.header(),
+                leaves: block
+                    .transactions
+                    .iter()
+                    .map(|t| (t.serial, t.merkle_leaf()))
+                    .collect(),
+            });
+        }
+        archive.sort_by_key(|block| block.info.serial);
+        let mut seen = self.seen.iter().copied().collect::<Vec<_>>();
+        seen.sort();
+        Snapshot {
+            len: self.len,
+            last: None,
+            params: self.params.clone(),
+            tip: self.tip,
+            blocks,
+            ledger: self.ledger.clone(),
+            transactions,
+            archive,
+            seen: Some(seen),
+        }
+    }
+
+    /// The number of messages in the log the chain has seen.
+    pub fn log_len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether a message with `hash`, see [`NewMessage::hash`], is already
+    /// in the log.
+    pub fn has_seen(&self, hash: &Hash) -> bool {
+        self.seen.contains(hash)
+    }
+
+    /// The hash of the newest block on the canonical branch, if any.
+    pub fn tip(&self) -> Option<Hash> {
+        self.tip
+    }
+
+    pub fn tip_info(&self) -> Option<&BlockInfo> {
+        self.tip.and_then(|tip| self.blocks.get(&tip))
+    }
+
+    pub fn get(&self, hash: &Hash) -> Option<&BlockInfo> {
+        self.blocks.get(hash)
+    }
+
+    /// The balances as of the canonical tip.
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    pub fn params(&self) -> &Params {
+        &self.params
+    }
+
+    /// The blocks on the canonical branch, from the tip back to its root.
+    pub fn canonical(&self) -> impl Iterator<Item = &BlockInfo> {
+        self.ancestors(self.tip_info())
+    }
+
+    /// `block` and the blocks before it, back to the root of its branch.
+    fn ancestors<'a>(
+        &'a self,
+        block: Option<&'a BlockInfo>,
+    ) -> impl Iterator<Item = &'a BlockInfo> {
+        std::iter::successors(block, |b| {
+            b.prev_hash.and_then(|prev| self.blocks.get(&prev))
+        })
+    }
+
+    /// The difficulty of the tip, and the difficulty of a block extending it.
+    pub fn difficulty(&self) -> (u32, u32) {
+        let tip = self.tip_info();
+        let current = tip.map_or(self.params.difficulty.initial, |b| b.difficulty);
+        (current, self.next_difficulty(tip))
+    }
+
+    /// The difficulty a block extending `prev` has to meet.
+    pub fn next_difficulty(&self, prev: Option<&BlockInfo>) -> u32 {
+        let schedule = &self.params.difficulty;
+        let prev = match prev {
+            Some(prev) => prev,
+            None => return schedule.initial,
+        };
+        if !schedule.is_retarget(prev.height + 1) {
+            return prev.difficulty;
+        }
+
+        // time the interval from the block just before it
+        let start = self
+            .ancestors(Some(prev))
+            .nth(schedule.retarget_interval as usize);
+        match (start.and_then(|b| b.timestamp), prev.timestamp) {
+            (Some(start), Some(end)) => {
+                schedule.retarget(prev.difficulty, end.saturating_sub(start))
+            }
+            // blocks from before timestamps can't be timed
+            _ => prev.difficulty,
+        }
+    }
+
+    /// The median timestamp of `prev` and the blocks before it.
+    fn median_time(&self, prev: Option<&BlockInfo>) -> Option<u64> {
+        let mut timestamps = self
+            .ancestors(prev)
+            .take(MEDIAN_TIME_SPAN)
+            .filter_map(|b| b.timestamp)
+            .collect::<Vec<_>>();
+        difficulty::median_time(&mut timestamps)
+    }
+
+    fn is_canonical(&self, block: &BlockInfo) -> bool {
+        let tip = match self.tip_info() {
+            Some(tip) if tip.height >= block.height => tip,
+            _ => return false,
+        };
+        self.canonical()
+            .nth((tip.height - block.height) as usize)
+            .is_some_and(|b| b.hash == block.hash)
+    }
+
+    /// The canonical block the transaction at `serial` is in, its header,
+    /// and the steps from the transaction up to the block's Merkle root.
+    pub fn proof(&self, serial: u64) -> Result<(&BlockInfo, String, Vec<Step>), String> {
+        let found = self
+            .containing
+            .get(&serial)
+            .into_iter()
+            .flatten()
+            .filter_map(|hash| Some((self.blocks.get(hash)?, self.bodies.get(hash)?)))
+            .find(|(info, _)| self.is_canonical(info));
+        let (info, header, leaves) = match (found, self.archive.get(&serial)) {
+            (Some((_, block)), _) if block.merkle_root.is_none() => {
+                return Err(format!(
+                    "Transaction {} is in a block without a Merkle root",
+                    serial
+                ))
+            }
+            (Some((info, block)), _) => (
+                info,
+                block.header(),
+                block
+                    .transactions
+                    .iter()
+                    .map(|t| (t.serial, t.merkle_leaf()))
+                    .collect(),
+            ),
+            (None, Some(block)) => (&block.info, block.header.clone(), block.leaves.clone()),
+            (None, None) => return Err(format!("Transaction {} isn't in a block", serial)),
+        };
+
+        let index = leaves.iter().position(|(s, _)| *s == serial).unwrap();
+        let leaves = leaves.into_iter().map(|(_, leaf)| leaf).collect::<Vec<_>>();
+        Ok((info, header, merkle::proof(&leaves, index).unwrap()))
+    }
+
+    /// The blocks that aren't on the canonical branch, oldest first.
+    pub fn orphans(&self) -> Vec<&BlockInfo> {
+        let canonical = self.canonical().map(|b| b.hash).collect::<HashSet<_>>();
+        let mut orphans = self
+            .blocks
+            .values()
+            .filter(|b| !canonical.contains(&b.hash))
+            .collect::<Vec<_>>();
+        orphans.sort_by_key(|b| b.serial);
+        orphans
+    }
+
+    /// The transactions that aren't in a block on the canonical branch yet,
+    /// with their serials, highest fee per byte first and oldest first among
+    /// equals.
+    pub fn mempool(&self) -> Vec<(u64, &NewTransaction)> {
+        let mut pending = self
+            .transactions
+            .iter()
+            .filter(|(serial, _)| !self.ledger.is_included(**serial))
+            .map(|(serial, (_, t))| (*serial, t, t.canonical_bytes().len() as u128))
+            .collect::<Vec<_>>();
+
+        // compare fee / len without dividing, so rates are exact
+        pending.sort_by(|(a_serial, a, a_len), (b_serial, b, b_len)| {
+            let a_rate = a.fee.units() as u128 * b_len;
+            let b_rate = b.fee.units() as u128 * a_len;
+            b_rate.cmp(&a_rate).then(a_serial.cmp(b_serial))
+        });
+        pending
+            .into_iter()
+            .map(|(serial, t, _)| (serial, t))
+            .collect()
+    }
+
+    /// Checks that a posted message can be appended to the log.
+    pub fn check(&self, message: &NewMessage) -> Result<(), String> {
+        self.check_with(message, false)
+    }
+
+    /// Like [`Chain::check`], but for a message already in the log, which may
+    /// predate some of the rules: blocks without a prev hash extend the tip,
+    /// and blocks without a timestamp aren't timed.
+    pub fn audit(&self, message: &NewMessage) -> Result<(), String> {
+        self.check_with(message, true)
+    }
+
+    fn check_with(&self, message: &NewMessage, logged: bool) -> Result<(), String> {
+        let block = match message {
+            NewMessage::NewBlock(b) => b,
+            NewMessage::NewTransaction(_) => return Ok(()),
+        };
+
+        if self.blocks.contains_key(&block.hash()) {
+            return Err("Block is already in the chain".to_string());
+        }
+
+        let prev = match block.prev_hash {
+            Some(prev) => match self.blocks.get(&prev) {
+                Some(prev) => Some(prev),
+                None => return Err("Block's prev hash is not a known block".to_string()),
+            },
+            None if self.tip.is_none() => None,
+            None if logged => self.tip_info(),
+            None => return Err("Block is missing a prev hash".to_string()),
+        };
+
+        match block.timestamp {
+            Some(timestamp) if prev.is_some() => {
+                if self
+                    .median_time(prev)
+                    .is_some_and(|median| timestamp <= median)
+                {
+                    return Err(format!(
+                        "Block's timestamp is not after the median of the last {} blocks",
+                        MEDIAN_TIME_SPAN
+                    ));
+                }
+                if timestamp > unix_now() + MAX_FUTURE_DRIFT {
+                    return Err("Block's timestamp is too far in the future".to_string());
+                }
+            }
+            None if prev.is_some() && !logged => {
+                return Err("Block is missing a timestamp".to_string())
+            }
+            _ => {}
+        }
+
+        match block.merkle_root {
+            Some(root) if root != block.compute_merkle_root() => {
+                return Err("Block's Merkle root doesn't match its transactions".to_string())
+            }
+            None if prev.is_some() && !logged => {
+                return Err("Block is missing a Merkle root".to_string())
+            }
+            _ => {}
+        }
+
+        let difficulty = self.next_difficulty(prev);
+        if block.hash().leading_zeros() < difficulty {
+            return Err(format!(
+                "Block's hash doesn't meet the difficulty of {} bits",
+                difficulty
+            ));
+        }
+
+        let height = prev.map_or(0, |p| p.height + 1);
+        let ledger = self
+            .ledger_at(prev.map(|p| p.hash))
+            .ok_or_else(|| "Block forks off before the latest snapshot".to_string())?;
+        self.check_block(&ledger, block, self.len, height)?;
+        Ok(())
+    }
+
+    /// Checks a block at `serial` and `height` against the ledger of the
+    /// block it extends. Transactions can only be included once they're in
+    /// the log, so a block can't make up transactions of its own.
+    fn check_block(
+        &self,
+        ledger: &Ledger,
+        block: &NewBlock,
+        serial: u64,
+        height: u64,
+    ) -> Result<LedgerUpdate, String> {
+        for t in &block.transactions {
+            let logged = self.transactions.get(&t.serial).map(|(hash, _)| *hash);
+            if t.serial >= serial || logged != Some(t.hash()) {
+                return Err(format!("Transaction {} is not in the log", t.serial));
+            }
+        }
+        ledger.check_block(block, self.params.reward.reward(height))
+    }
+
+    /// The ledger as of `hash`, if it's the tip of a branch, or else worked
+    /// out with [`Chain::replay_ledger`].
+    fn ledger_at(&self, hash: Option<Hash>) -> Option<Cow<'_, Ledger>> {
+        if hash == self.tip {
+            return Some(Cow::Borrowed(&self.ledger));
+        }
+        match hash.and_then(|hash| self.branches.get(&hash)) {
+            Some(ledger) => Some(Cow::Borrowed(ledger)),
+            None => self.replay_ledger(hash).map(Cow::Owned),
+        }
+    }
+
+    /// Works out the ledger as of `hash` by applying every block from the
+    /// root of its branch, or from the snapshot the chain was picked up from.
+    /// Blocks that break the rules are skipped. Branches that fork off before
+    /// the snapshot have no ledger.
+    fn replay_ledger(&self, hash: Option<Hash>) -> Option<Ledger> {
+        let mut ledger = Ledger::new();
+        let mut branch = Vec::new();
+        for info in self.ancestors(hash.and_then(|h| self.blocks.get(&h))) {
+            if let Some((base, base_ledger)) = &self.base {
+                if info.hash == *base {
+                    ledger = base_ledger.clone();
+                    break;
+                }
+            }
+            if !self.bodies.contains_key(&info.hash) {
+                return None;
+            }
+            branch.push(info);
+        }
+
+        for info in branch.into_iter().rev() {
+            let block = &self.bodies[&info.hash];
+            if let Ok(update) = self.check_block(&ledger, block, info.serial, info.height) {
+                ledger.commit(update);
+            }
+        }
+        Some(ledger)
+    }
+
+    /// Records a message that was appended to the log at `serial`.
+    pub fn push(&mut self, message: &NewMessage, serial: u64) {
+        self.len = self.len.max(serial + 1);
+        self.seen.insert(message.hash());
+        let block = match message {
+            NewMessage::NewBlock(b) => b,
+            NewMessage::NewTransaction(t) => {
+                self.transactions.insert(serial, (t.hash(), t.clone()));
+                return;
+            }
+        };
+
+        let hash = block.hash();
+        if self.blocks.contains_key(&hash) {
+            return;
+        }
+
+        let prev = match block.prev_hash.or(self.tip) {
+            Some(prev) => match self.blocks.get(&prev) {
+                Some(prev) => Some(prev),
+                None => {
+                    warn!(serial, %prev, "Skipping block whose prev hash is not a known block");
+                    return;
+                }
+            },
+            None => None,
+        };
+        let difficulty = self.next_difficulty(prev);
+        let info = BlockInfo {
+            serial,
+            hash,
+            prev_hash: prev.map(|p| p.hash),
+            height: prev.map_or(0, |p| p.height + 1),
+            timestamp: block.timestamp,
+            difficulty,
+            work: prev
+                .map_or(0, |p| p.work)
+                .saturating_add(difficulty::work(difficulty)),
+        };
+        self.blocks.insert(hash, info);
+        self.bodies.insert(hash, block.clone());
+        for t in &block.transactions {
+            self.containing.entry(t.serial).or_default().push(hash);
+        }
+
+        let heaviest = self.tip_info().is_none_or(|tip| info.work > tip.work);
+        if info.prev_hash == self.tip && heaviest {
+            match self.check_block(&self.ledger, block, serial, info.height) {
+                Ok(update) => self.ledger.commit(update),
+                Err(e) => warn!(serial, error = %e, "Block doesn't count towards the ledger"),
+            }
+            self.tip = Some(hash);
+            return;
+        }
+
+        // the block extends a side branch, or starts one
+        let cached = info.prev_hash.and_then(|prev| self.branches.remove(&prev));
+        let mut ledger = match cached {
+            Some(ledger) => ledger,
+            None => match self.ledger_at(info.prev_hash) {
+                Some(ledger) => ledger.into_owned(),
+                None => {
+                    warn!(serial, "Block forks off before the latest snapshot");
+                    return;
+                }
+            },
+        };
+        match self.check_block(&ledger, block, serial, info.height) {
+            Ok(update) => ledger.commit(update),
+            Err(e) => warn!(serial, error = %e, "Block doesn't count towards the ledger"),
+        }
+
+        // reorganize onto the new block if its branch is now the heaviest
+        if heaviest {
+            let old = std::mem::replace(&mut self.ledger, ledger);
+            if let Some(tip) = self.tip.replace(hash) {
+                self.keep_branch(tip, old);
+            }
+        } else {
+            self.keep_branch(hash, ledger);
+        }
+    }
+
+    /// Keeps the ledger as of the tip of a side branch, forgetting the branch
+    /// extended longest ago if there are too many.
+    fn keep_branch(&mut self, tip: Hash, ledger: Ledger) {
+        self.branches.insert(tip, ledger);
+        if self.branches.len() > MAX_BRANCHES {
+            let oldest = self
+                .branches
+                .keys()
+                .min_by_key(|hash| self.blocks.get(hash).map_or(0, |b| b.serial))
+                .copied();
+            if let Some(oldest) = oldest {
+                self.branches.remove(&oldest);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod chain_tests {
+    use super::{Chain, Params};
+    use crate::{
+        amount::Amount,
+        difficulty::{self, DifficultySchedule},
+        hash::Hash,
+        merkle,
+        messages::{NewBlock, NewMessage},
+        test_util::{pay, tx, wallet, KEY, KEY2},
+    };
+
+    /// Rules that make blocks trivial to mine, starting from `difficulty`.
+    fn params(difficulty: u32) -> Params {
+        Params {
+            difficulty: DifficultySchedule {
+                initial: difficulty,
+                ..DifficultySchedule::default()
+            },
+            ..Params::default()
+        }
+    }
+
+    /// Mines a block paying KEY2 on top of `prev`, with the given embedded
+    /// transactions.
+    fn mine_with(chain: &Chain, prev: Hash, timestamp: u64, transactions: &str) -> NewMessage {
+        let difficulty = chain.next_difficulty(chain.get(&prev));
+        let mut block = format!(
+            "block:v3:{}:{}:0:{}:{}",
+            prev, timestamp, KEY2, transactions
+        )
+        .parse::<NewBlock>()
+        .unwrap();
+        block.merkle_root = Some(block.compute_merkle_root());
+        while block.hash().leading_zeros() < difficulty {
+            block.nonce += 1;
+        }
+        NewMessage::NewBlock(block)
+    }
+
+    fn mine(chain: &Chain, prev: Hash, timestamp: u64) -> NewMessage {
+        mine_with(chain, prev, timestamp, "")
+    }
+
+    fn genesis(params: Params) -> Chain {
+        Chain::replay(params, [NewBlock::genesis().to_string().as_str()])
+    }
+
+    /// A root block paying the test wallet, so it has coins to spend.
+    fn funded_root() -> NewBlock {
+        format!("block:1:{}:", wallet().account()).parse().unwrap()
+    }
+
+    /// `t` as it's embedded in a block at `serial`.
+    fn embed(serial: u64, t: &NewMessage) -> String {
+        format!("{};{}", serial, t.to_string().replace(':', ";"))
+    }
+
+    #[test]
+    fn test_replay_tracks_last_block() {
+        let genesis = NewBlock::genesis().to_string();
+        let tx = tx("Zm9v").to_string();
+        let chain = Chain::replay(params(0), [genesis.as_str(), tx.as_str()]);
+        assert_eq!(chain.tip(), Some(NewBlock::genesis().hash()));
+
+        // legacy blocks extend whatever came before them
+        let legacy = format!("block:1:{}:", KEY2);
+        let chain = Chain::replay(params(0), [genesis.as_str(), legacy.as_str(), "garbage"]);
+        let tip = chain.tip_info().unwrap();
+        assert_eq!(tip.hash, legacy.parse::<NewMessage>().unwrap().hash());
+        assert_eq!(tip.serial, 1);
+        assert_eq!(tip.height, 1);
+        assert_eq!(tip.prev_hash, Some(NewBlock::genesis().hash()));
+
+        // nonces written as floats are rounded
+        let float = format!("block:1.5:{}:", KEY2);
+        let chain = Chain::replay(params(0), [genesis.as_str(), float.as_str()]);
+        let rounded = format!("block:2:{}:", KEY2).parse::<NewMessage>().unwrap();
+        assert_eq!(chain.tip(), Some(rounded.hash()));
+    }
+
+    #[test]
+    fn test_check_prev_hash() {
+        let mut chain = genesis(params(0));
+        let root = NewBlock::genesis().hash();
+        let next = mine(&chain, root, 100);
+        assert!(chain.check(&next).is_ok());
+
+        let legacy = format!("block:1:{}:", KEY2).parse::<NewMessage>().unwrap();
+        assert!(chain.check(&legacy).is_err());
+        assert!(chain.check(&mine(&chain, Hash::ZERO, 100)).is_err());
+
+        // but it was fine before blocks were linked
+        assert!(chain.audit(&legacy).is_ok());
+        assert!(chain.audit(&mine(&chain, Hash::ZERO, 100)).is_err());
+
+        chain.push(&next, 1);
+        assert_eq!(chain.tip(), Some(next.hash()));
+        assert_eq!(
+            chain.check(&next).err().unwrap(),
+            "Block is already in the chain"
+        );
+    }
+
+    #[test]
+    fn test_reorganizes_to_heaviest_branch() {
+        let mut chain = genesis(params(0));
+        let root = NewBlock::genesis().hash();
+
+        let a = mine(&chain, root, 100);
+        chain.push(&a, 1);
+        assert_eq!(chain.tip(), Some(a.hash()));
+
+        // a competing block with the same work doesn't move the tip
+        let b = mine(&chain, root, 101);
+        assert!(chain.check(&b).is_ok());
+        chain.push(&b, 2);
+        assert_eq!(chain.tip(), Some(a.hash()));
+        assert_eq!(chain.orphans().len(), 1);
+        assert_eq!(chain.orphans()[0].hash, b.hash());
+
+        // but extending it past the other branch does
+        let c = mine(&chain, b.hash(), 102);
+        chain.push(&c, 3);
+        assert_eq!(chain.tip(), Some(c.hash()));
+        let tip = chain.tip_info().unwrap();
+        assert_eq!(tip.height, 2);
+        assert_eq!(tip.work, 3 * difficulty::work(0));
+
+        let orphans = chain.orphans();
+        assert_eq!(orphans.len(), 1);
+        assert_eq!(orphans[0].hash, a.hash());
+        assert_eq!(orphans[0].serial, 1);
+        assert_eq!(
+            chain.canonical().map(|b| b.serial).collect::<Vec<_>>(),
+            vec![3, 2, 0]
+        );
+    }
+
+    #[test]
+    fn test_blocks_only_include_logged_transactions() {
+        let root = funded_root();
+        let posted = pay("Zm9v", &format!("{},5", KEY2));
+        let log = [root.to_string(), posted.to_string()];
+        let mut chain = Chain::replay(params(0), log.iter().map(String::as_str));
+        let sender = wallet().account();
+        assert_eq!(chain.ledger().balance(&sender), Amount::from_whole(50));
+
+        // a transaction that was never posted
+        let unposted = embed(1, &pay("YmFy", &format!("{},5", KEY2)));
+        let block = mine_with(&chain, root.hash(), 100, &unposted);
+        assert_eq!(
+            chain.check(&block).err().unwrap(),
+            "Transaction 1 is not in the log"
+        );
+
+        // or was posted differently
+        let embedded = embed(1, &posted);
+        let forged = mine_with(&chain, root.hash(), 100, &embedded.replace(",5", ",50"));
+        assert_eq!(
+            chain.check(&forged).err().unwrap(),
+            "Transaction 1 is not in the log"
+        );
+        let forged = mine_with(&chain, root.hash(), 100, &embedded.replacen('1', "0", 1));
+        assert!(chain.check(&forged).is_err());
+
+        let ok = mine_with(&chain, root.hash(), 100, &embedded);
+        assert!(chain.check(&ok).is_ok());
+        chain.push(&ok, 2);
+        assert_eq!(chain.ledger().balance(&sender), Amount::from_whole(45));
+        assert_eq!(chain.ledger().balance(KEY2), Amount::from_whole(55));
+    }
+
+    #[test]
+    fn test_ledger_follows_reorganization() {
+        let mut chain = genesis(params(0));
+        let root = NewBlock::genesis().hash();
+
+        let a = mine(&chain, root, 100);
+        chain.push(&a, 1);
+        let b = mine(&chain, root, 101);
+        chain.push(&b, 2);
+        // both branches pay KEY2, but only the canonical one counts
+        assert_eq!(chain.ledger().balance(KEY2), Amount::from_whole(50));
+
+        let c = mine(&chain, b.hash(), 102);
+        chain.push(&c, 3);
+        assert_eq!(chain.tip(), Some(c.hash()));
+        assert_eq!(chain.ledger().balance(KEY2), Amount::from_whole(100));
+        assert_eq!(chain.ledger().balance(KEY), Amount::from_whole(50));
+
+        // and back again, picking up from where the first branch left off
+        let d = mine(&chain, a.hash(), 103);
+        chain.push(&d, 4);
+        assert_eq!(chain.tip(), Some(c.hash()));
+        let e = mine(&chain, d.hash(), 104);
+        assert!(chain.check(&e).is_ok());
+        chain.push(&e, 5);
+        assert_eq!(chain.tip(), Some(e.hash()));
+        assert_eq!(chain.ledger().balance(KEY2), Amount::from_whole(150));
+    }
+
+    #[test]
+    fn test_leaves_out_blocks_with_unknown_prev_hash() {
+        let mut chain = genesis(params(0));
+        let stray = mine(&chain, Hash::ZERO, 100);
+        chain.push(&stray, 1);
+        assert_eq!(chain.tip(), Some(NewBlock::genesis().hash()));
+        assert!(chain.get(&stray.hash()).is_none());
+        assert!(chain.orphans().is_empty());
+        assert_eq!(chain.log_len(), 2);
+    }
+
+    #[test]
+    fn test_mempool_orders_by_fee_rate() {
+        let tx = |unique: &str, fee: &str| pay(unique, &format!("{},1{}", KEY2, fee)).to_string();
+        let root = funded_root();
+        let log = [
+            root.to_string(),
+            tx("Zm9v", ""),
+            tx("YmFy", ":fee,0.5"),
+            // the same fee spread over more bytes is a lower rate
+            tx("YmFyYmFyYmFy", ":fee,0.5"),
+            tx("YmF6", ":fee,2"),
+            tx("cXV4", ""),
+        ];
+        let mut chain = Chain::replay(params(0), log.iter().map(String::as_str));
+        let serials = |chain: &Chain| {
+            chain
+                .mempool()
+                .iter()
+                .map(|(serial, _)| *serial)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(serials(&chain), vec![4, 2, 3, 1, 5]);
+
+        let embedded = format!("4;{}", tx("YmF6", ":fee,2").replace(':', ";"));
+        let included = mine_with(&chain, root.hash(), 100, &embedded);
+        assert!(chain.check(&included).is_ok());
+        chain.push(&included, 6);
+        assert_eq!(serials(&chain), vec![2, 3, 1, 5]);
+        assert_eq!(chain.ledger().balance(KEY2), Amount::from_whole(53));
+    }
+
+    #[test]
+    fn test_checks_proof_of_work() {
+        let chain = genesis(params(8));
+        let root = NewBlock::genesis().hash();
+        let easy = (0..)
+            .map(|nonce| {
+                format!("block:v4:{}:100:{}:{}:{}:", root, Hash::ZERO, nonce, KEY2)
+                    .parse::<NewMessage>()
+                    .unwrap()
+            })
+            .find(|b| b.hash().leading_zeros() < 8)
+            .unwrap();
+        assert_eq!(
+            chain.check(&easy).err().unwrap(),
+            "Block's hash doesn't meet the difficulty of 8 bits"
+        );
+
+        let mined = mine(&chain, root, 100);
+        assert!(mined.hash().leading_zeros() >= 8);
+        assert!(chain.check(&mined).is_ok());
+    }
+
+    #[test]
+    fn test_proves_inclusion() {
+        let root = funded_root();
+        let log = [
+            root.to_string(),
+            tx("Zm9v").to_string(),
+            tx("YmFy").to_string(),
+            tx("YmF6").to_string(),
+        ];
+        let mut chain = Chain::replay(params(0), log.iter().map(String::as_str));
+        let embedded = (1..=3)
+            .map(|serial| format!("{};{}", serial, log[serial].replace(':', ";")))
+            .collect::<Vec<_>>()
+            .join(":");
+
+        // the root has to be the one of the transactions
+        let block = mine_with(&chain, root.hash(), 100, &embedded);
+        let mut forged = block.to_string().parse::<NewBlock>().unwrap();
+        forged.merkle_root = Some(Hash::ZERO);
+        assert_eq!(
+            chain.check(&NewMessage::NewBlock(forged)).err().unwrap(),
+            "Block's Merkle root doesn't match its transactions"
+        );
+
+        assert!(chain.check(&block).is_ok());
+        chain.push(&block, 4);
+        assert_eq!(
+            chain.proof(4).err().unwrap(),
+            "Transaction 4 isn't in a block"
+        );
+
+        for serial in 1..=3 {
+            let (info, header, steps) = chain.proof(serial).unwrap();
+            assert_eq!(info.serial, 4);
+            let leaf = Hash::of(format!("{}:{}", serial, log[serial as usize]).as_bytes());
+            let body = format!("{}:", header).parse::<NewBlock>().unwrap();
+            assert_eq!(Some(merkle::fold(leaf, &steps)), body.merkle_root);
+            assert_eq!(Hash::of(header.as_bytes()), info.hash);
+        }
+    }
+
+    #[test]
+    fn test_checks_timestamps() {
+        let mut chain = genesis(params(0));
+        let mut prev = NewBlock::genesis().hash();
+
+        // blocks without a timestamp aren't accepted anymore
+        let v2 = format!("block:v2:{}:1:{}:", prev, KEY2)
+            .parse::<NewMessage>()
+            .unwrap();
+        assert_eq!(
+            chain.check(&v2).err().unwrap(),
+            "Block is missing a timestamp"
+        );
+
+        for (serial, timestamp) in [100, 90, 300, 110, 120].into_iter().enumerate() {
+            let block = mine(&chain, prev, timestamp);
+            chain.push(&block, serial as u64 + 1);
+            prev = block.hash();
+        }
+
+        // the median of the last blocks is 110, so a little out of order is
+        // fine but not before that
+        assert!(chain.check(&mine(&chain, prev, 111)).is_ok());
+        assert_eq!(
+            chain.check(&mine(&chain, prev, 110)).err().unwrap(),
+            "Block's timestamp is not after the median of the last 11 blocks"
+        );
+        assert_eq!(
+            chain
+                .check(&mine(&chain, prev, u64::MAX / 2))
+                .err()
+                .unwrap(),
+            "Block's timestamp is too far in the future"
+        );
+    }
+
+    #[test]
+    fn test_retargets_difficulty() {
+        let params = Params {
+            difficulty: DifficultySchedule {
+                initial: 1,
+                retarget_interval: 2,
+                target_block_time: 60,
+            },
+            ..Params::default()
+        };
+        let mut chain = genesis(params);
+        let mut prev = NewBlock::genesis().hash();
+        let mut push = |chain: &mut Chain, timestamp| {
+            let block = mine(chain, prev, timestamp);
+            assert!(chain.check(&block).is_ok());
+            chain.push(&block, chain.tip_info().unwrap().serial + 1);
+            prev = block.hash();
+        };
+
+        // the first interval can't be timed since genesis has no timestamp
+        push(&mut chain, 1000);
+        assert_eq!(chain.difficulty(), (1, 1));
+        push(&mut chain, 1001);
+        push(&mut chain, 1002);
+
+        // two blocks in two seconds is far faster than one a minute
+        assert_eq!(chain.difficulty(), (1, 2));
+        push(&mut chain, 1003);
+        assert_eq!(chain.difficulty(), (2, 2));
+
+        // and two in a thousand seconds is far slower
+        push(&mut chain, 2000);
+        assert_eq!(chain.difficulty(), (2, 1));
+        push(&mut chain, 2001);
+        assert_eq!(chain.tip_info().unwrap().difficulty, 1);
+    }
+}
@@ -0,0 +1,313 @@
+//! A client for a racketchain server, speaking the same line format the
+//! server does.
+
+use std::{fs::File, io::BufReader, time::Duration};
+
+use futures::{stream, Stream};
+use hyper::{client::HttpConnector, header, Body, Method, Request, StatusCode};
+use hyper_rustls::{ConfigBuilderExt, HttpsConnector, HttpsConnectorBuilder};
+
+use crate::{
+    hash::Hash,
+    messages::{Message, NewMessage, Transaction},
+};
+
+/// Talks to the server at a base url like `http://127.0.0.1:8080`, or one
+/// like `https://example.com` for a server behind TLS.
+#[derive(Clone)]
+pub struct Client {
+    base: String,
+    http: hyper::Client<HttpsConnector<HttpConnector>>,
+    // sent as a bearer token with every request, for admin routes
+    token: Option<String>,
+}
+
+impl Client {
+    /// A client trusting the usual certificate authorities over https.
+    pub fn new(base: &str) -> Self {
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_webpki_roots()
+            .with_no_client_auth();
+        Client::with_config(base, config)
+    }
+
+    /// A client trusting only the certificate authorities in the PEM file at
+    /// `ca` over https, e.g. for servers with self-signed certificates.
+    pub fn with_ca(base: &str, ca: &str) -> Result<Self, String> {
+        let file = File::open(ca).map_err(|e| format!("Failed to open {}: {}", ca, e))?;
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut BufReader::new(file)) {
+            let cert = cert.map_err(|e| format!("Failed to read {}: {}", ca, e))?;
+            roots
+                .add(&rustls::Certificate(cert.to_vec()))
+                .map_err(|e| format!("{} has a bad certificate: {}", ca, e))?;
+        }
+        if roots.is_empty() {
+            return Err(format!("{} has no certificates", ca));
+        }
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(Client::with_config(base, config))
+    }
+
+    /// A client trusting the certificate authorities in `ca` if given, see
+    /// [`Client::with_ca`], or the usual ones otherwise.
+    pub fn connect(base: &str, ca: Option<&str>) -> Result<Self, String> {
+        match ca {
+            Some(ca) => Client::with_ca(base, ca),
+            None => Ok(Client::new(base)),
+        }
+    }
+
+    fn with_config(base: &str, config: rustls::ClientConfig) -> Self {
+        let https = HttpsConnectorBuilder::new()
+            .with_tls_config(config)
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Client {
+            base: base.trim_end_matches('/').to_string(),
+            http: hyper::Client::builder().build(https),
+            token: None,
+        }
+    }
+
+    /// The same client, but sending `token` for admin routes.
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    /// Sends a request, returning the status and body of the response.
+    pub async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        body: String,
+    ) -> Result<(StatusCode, String), String> {
+        let mut req = Request::builder()
+            .method(method)
+            .uri(format!("{}{}", self.base, path));
+        if let Some(token) = &self.token {
+            req = req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let req = req.body(Body::from(body)).map_err(|e| e.to_string())?;
+        let res = self.http.request(req).await.map_err(|e| e.to_string())?;
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body())
+            .await
+            .map_err(|e| e.to_string())?;
+        let body = String::from_utf8(body.to_vec()).map_err(|e| e.to_string())?;
+        Ok((status, body))
+    }
+
+    /// Sends a request, returning the body of the response or, if the server
+    /// turned it down, the error it gave.
+    async fn request(&self, method: Method, path: &str, body: String) -> Result<String, String> {
+        let (status, body) = self.send(method, path, body).await?;
+        if status != StatusCode::OK {
+            return Err(body.trim_end().to_string());
+        }
+        Ok(body)
+    }
+
+    async fn get(&self, path: &str) -> Result<String, String> {
+        self.request(Method::GET, path, String::new()).await
+    }
+
+    pub fn base(&self) -> &str {
+        &self.base
+    }
+
+    /// Posts a message, which the server appends to the log if it's valid.
+    pub async fn post(&self, message: &NewMessage) -> Result<(), String> {
+        self.request(Method::POST, "/", message.to_string())
+            .await
+            .map(|_| ())
+    }
+
+    /// Passes on a message another server accepted, see [`crate::gossip`].
+    pub async fn relay(&self, message: &NewMessage) -> Result<(), String> {
+        self.request(Method::POST, "/relay", message.to_string())
+            .await
+            .map(|_| ())
+    }
+
+    /// The number of messages in the log.
+    pub async fn log_len(&self) -> Result<u64, String> {
+        let body = self.get("/len").await?;
+        body.trim_end()
+            .parse()
+            .map_err(|_| "Length is not a number".to_string())
+    }
+
+    /// The serial and hash of the canonical tip.
+    pub async fn tip(&self) -> Result<(u64, Hash), String> {
+        let body = self.get("/tip").await?;
+        let mut fields = body.trim_end().split(':');
+        let serial = fields
+            .next()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| "Tip has no serial".to_string())?;
+        let hash = fields.next().unwrap_or("").parse()?;
+        Ok((serial, hash))
+    }
+
+    /// The difficulty of the tip and of the block after it.
+    pub async fn difficulty(&self) -> Result<(u32, u32), String> {
+        let body = self.get("/difficulty").await?;
+        let parse = |s: Option<&str>| s.and_then(|s| s.parse().ok());
+        let mut fields = body.trim_end().split(':');
+        parse(fields.next())
+            .zip(parse(fields.next()))
+            .ok_or_else(|| "Difficulty is not two numbers".to_string())
+    }
+
+    /// The transactions not in a block yet, highest fee rate first. Any the
+    /// client can't read are left out.
+    pub async fn mempool(&self) -> Result<Vec<Transaction>, String> {
+        let body = self.get("/mempool").await?;
+        Ok(body.lines().filter_map(|line| line.parse().ok()).collect())
+    }
+
+    /// The headers of the blocks from `from` on, a page at a time, with their
+    /// serials.
+    pub async fn headers_from(&self, from: u64) -> Result<Vec<(u64, String)>, String> {
+        let body = self.get(&format!("/headers?from={}", from)).await?;
+        body.lines().map(split_serial).collect()
+    }
+
+    /// Like [`Client::fetch_from`], but with each message as it's stored,
+    /// along with its serial.
+    pub async fn lines_from(&self, id: u64) -> Result<Vec<(u64, String)>, String> {
+        let body = self.get(&format!("/{}", id)).await?;
+        body.lines().map(split_serial).collect()
+    }
+
+    /// Fetches the next page of messages from `id` on, which is empty once
+    /// there are none. Each message comes back parsed, or as the reason it
+    /// couldn't be.
+    pub async fn fetch_from(&self, id: u64) -> Result<Vec<Result<Message, String>>, String> {
+        let body = self.get(&format!("/{}", id)).await?;
+        Ok(body.lines().map(str::parse).collect())
+    }
+
+    /// Every message in the log from `id` on, ending once there are no more.
+    pub fn messages_from(&self, id: u64) -> impl Stream<Item = Result<Message, String>> + '_ {
+        self.stream_from(id, None)
+    }
+
+    /// Every message in the log, oldest first.
+    pub fn messages(&self) -> impl Stream<Item = Result<Message, String>> + '_ {
+        self.messages_from(0)
+    }
+
+    /// Every message in the log from `id` on, then every message posted
+    /// after that as it shows up, checking for new ones every `interval`.
+    pub fn follow(
+        &self,
+        id: u64,
+        interval: Duration,
+    ) -> impl Stream<Item = Result<Message, String>> + '_ {
+        self.stream_from(id, Some(interval))
+    }
+
+    fn stream_from(
+        &self,
+        id: u64,
+        interval: Option<Duration>,
+    ) -> impl Stream<Item = Result<Message, String>> + '_ {
+        let pages = stream::unfold(Some(id), move |next| async move {
+            let mut next = next?;
+            loop {
+                let page = match self.fetch_from(next).await {
+                    Ok(page) => page,
+                    // stop after a failed request, since it'd likely fail again
+                    Err(e) => return Some((vec![Err(e)], None)),
+                };
+                if page.is_empty() {
+                    tokio::time::sleep(interval?).await;
+                    continue;
+                }
+                next += page.len() as u64;
+                return Some((page, Some(next)));
+            }
+        });
+        futures::StreamExt::flat_map(pages, stream::iter)
+    }
+}
+
+/// Splits a `serial:...` line the server sent into the serial and the rest.
+fn split_serial(line: &str) -> Result<(u64, String), String> {
+    let (serial, rest) = line
+        .split_once(':')
+        .ok_or_else(|| "Line has no serial".to_string())?;
+    let serial = serial
+        .parse()
+        .map_err(|_| "Serial is not a number".to_string())?;
+    Ok((serial, rest.to_string()))
+}
+
+#[cfg(test)]
+mod client_tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+
+    use crate::{
+        messages::{Message, NewBlock},
+        test_util::{serve, tx, KEY2},
+    };
+
+    #[tokio::test]
+    async fn test_post_and_fetch() {
+        let client = serve(0);
+        client.post(&tx("Zm9v")).await.unwrap();
+
+        let page = client.fetch_from(0).await.unwrap();
+        assert_eq!(page.len(), 2);
+        match &page[1] {
+            Ok(Message::Transaction(t)) => assert_eq!(t.serial, 1),
+            _ => panic!("expected a transaction"),
+        }
+        assert!(client.fetch_from(2).await.unwrap().is_empty());
+
+        assert_eq!(client.log_len().await.unwrap(), 2);
+        assert_eq!(client.tip().await.unwrap(), (0, NewBlock::genesis().hash()));
+        assert_eq!(client.difficulty().await.unwrap(), (0, 0));
+        assert_eq!(client.mempool().await.unwrap()[0].serial, 1);
+        assert_eq!(
+            client.headers_from(0).await.unwrap(),
+            vec![(0, NewBlock::genesis().header())]
+        );
+
+        // the server's reason comes back as the error
+        let block = format!("block:1:{}:", KEY2).parse().unwrap();
+        assert_eq!(
+            client.post(&block).await.err().unwrap(),
+            "Error: Block is missing a prev hash"
+        );
+
+        let all = client.messages().collect::<Vec<_>>().await;
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].as_ref().unwrap().hash(), NewBlock::genesis().hash());
+    }
+
+    #[tokio::test]
+    async fn test_follow() {
+        let client = serve(0);
+        let mut follow = Box::pin(client.follow(1, Duration::from_millis(50)));
+
+        let poster = client.clone();
+        tokio::spawn(async move { poster.post(&tx("YmFy")).await.unwrap() });
+        let next = tokio::time::timeout(Duration::from_secs(10), follow.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(next.hash(), tx("YmFy").hash());
+    }
+}
@@ -0,0 +1,112 @@
+//! How hard blocks are to mine, and how that changes over time.
+
+use serde::{Deserialize, Serialize};
+
+/// How many of the latest timestamps a new block's timestamp is compared to.
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// How far into the future a block's timestamp may be, in seconds.
+pub const MAX_FUTURE_DRIFT: u64 = 2 * 60 * 60;
+
+/// Difficulty is the number of leading zero bits a block's hash needs. Every
+/// `retarget_interval` blocks it moves one bit towards whatever would have
+/// made those blocks arrive `target_block_time` seconds apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DifficultySchedule {
+    pub initial: u32,
+    pub retarget_interval: u64,
+    pub target_block_time: u64,
+}
+
+impl Default for DifficultySchedule {
+    fn default() -> Self {
+        DifficultySchedule {
+            initial: 16,
+            retarget_interval: 10,
+            target_block_time: 60,
+        }
+    }
+}
+
+impl DifficultySchedule {
+    /// Whether the block at `height` gets a new difficulty.
+    pub fn is_retarget(&self, height: u64) -> bool {
+        self.retarget_interval != 0 && height != 0 && height.is_multiple_of(self.retarget_interval)
+    }
+
+    /// The difficulty after `retarget_interval` blocks at `current` took
+    /// `span` seconds. Each bit halves or doubles the expected time, so the
+    /// difficulty only moves when the blocks were off by more than that.
+    pub fn retarget(&self, current: u32, span: u64) -> u32 {
+        let expected = self
+            .target_block_time
+            .saturating_mul(self.retarget_interval);
+        if span.saturating_mul(2) < expected {
+            (current + 1).min(255)
+        } else if span > expected.saturating_mul(2) {
+            current.saturating_sub(1)
+        } else {
+            current
+        }
+    }
+}
+
+/// The work a block at `difficulty` proves, i.e. how many hashes it takes to
+/// find one on average.
+pub fn work(difficulty: u32) -> u128 {
+    1u128.checked_shl(difficulty).unwrap_or(u128::MAX)
+}
+
+/// The median of some block timestamps, which a new block's timestamp must
+/// be past. Using the median lets clocks disagree a little without letting
+/// time run backwards.
+pub fn median_time(timestamps: &mut [u64]) -> Option<u64> {
+    if timestamps.is_empty() {
+        return None;
+    }
+    timestamps.sort_unstable();
+    Some(timestamps[timestamps.len() / 2])
+}
+
+#[cfg(test)]
+mod difficulty_tests {
+    use super::{median_time, work, DifficultySchedule};
+
+    #[test]
+    fn test_retarget_moves_towards_target() {
+        let schedule = DifficultySchedule {
+            initial: 8,
+            retarget_interval: 10,
+            target_block_time: 60,
+        };
+        assert!(!schedule.is_retarget(0));
+        assert!(!schedule.is_retarget(9));
+        assert!(schedule.is_retarget(10));
+
+        // on target, or within a factor of two of it
+        assert_eq!(schedule.retarget(8, 600), 8);
+        assert_eq!(schedule.retarget(8, 301), 8);
+        assert_eq!(schedule.retarget(8, 1200), 8);
+
+        assert_eq!(schedule.retarget(8, 299), 9);
+        assert_eq!(schedule.retarget(8, 0), 9);
+        assert_eq!(schedule.retarget(8, 1201), 7);
+        assert_eq!(schedule.retarget(0, u64::MAX), 0);
+        assert_eq!(schedule.retarget(255, 0), 255);
+    }
+
+    #[test]
+    fn test_work_doubles_per_bit() {
+        assert_eq!(work(0), 1);
+        assert_eq!(work(16), 65536);
+        assert_eq!(work(200), u128::MAX);
+    }
+
+    #[test]
+    fn test_median_time() {
+        assert_eq!(median_time(&mut []), None);
+        assert_eq!(median_time(&mut [5]), Some(5));
+        assert_eq!(median_time(&mut [9, 1, 5]), Some(5));
+        assert_eq!(median_time(&mut [4, 1, 3, 2]), Some(3));
+    }
+}
@@ -0,0 +1,263 @@
+//! Relaying messages between servers.
+//!
+//! A server can be given peers, which are other servers it passes every
+//! message it accepts on to, through `POST /relay`. Relays skip the rate
+//! limit posts are held to, so they're only taken from servers that send the
+//! relay token they all share. A server turns away messages that are already
+//! in its log, by the hash of the message, so a message goes around each loop
+//! of peers only once, however it's written. When it starts, a server copies whatever its
+//! peers have past the end of its own log, as long as its log is the start of
+//! theirs, checking every message like it would a post.
+//!
+//! Servers only agree on serials as long as messages reach them in the same
+//! order. Blocks are checked against each server's own log, so a server
+//! turns away a block that includes transactions by serials it has
+//! differently.
+
+use tracing::{info, warn, Instrument};
+
+use crate::{chain::Chain, client::Client, messages::NewMessage, storage::Storage, verify};
+
+/// Passes `message` on to every peer in the background.
+pub fn relay(peers: &[Client], message: &NewMessage) {
+    for peer in peers {
+        let (peer, message) = (peer.clone(), message.clone());
+        tokio::spawn(
+            async move {
+                if let Err(e) = peer.relay(&message).await {
+                    warn!(peer = peer.base(), error = %e, "Failed to relay");
+                }
+            }
+            .in_current_span(),
+        );
+    }
+}
+
+/// Copies the messages each peer has past the end of `storage` in turn,
+/// replaying them onto `chain`. Peers whose log
+/// doesn't start with this one are skipped, and copying from a peer stops at
+/// the first message that breaks the rules, since the ones after it would
+/// end up with other serials.
+pub async fn catch_up(storage: &mut (dyn Storage + Send), chain: &mut Chain, peers: &[Client]) {
+    for peer in peers {
+        match catch_up_from(storage, chain, peer).await {
+            Ok(0) => {}
+            Ok(copied) => info!(copied, peer = peer.base(), "Caught up from a peer"),
+            Err(e) => warn!(peer = peer.base(), error = %e, "Failed to catch up"),
+        }
+    }
+}
+
+async fn catch_up_from(
+    storage: &mut (dyn Storage + Send),
+    chain: &mut Chain,
+    peer: &Client,
+) -> Result<u64, String> {
+    let len = storage.len()?;
+    let mut next = len;
+    loop {
+        let last = match next {
+            0 => None,
+            next => storage.range(next - 1, next)?.pop(),
+        };
+        let mut lines = fetch_after(peer, next, last.as_deref()).await?;
+        if lines.is_empty() {
+            break;
+        }
+
+        // the chain has to take each message before the next can be checked
+        let mut invalid = None;
+        for (i, (serial, line)) in (next..).zip(&lines).enumerate() {
+            match verify::audit_entry(chain, serial, line) {
+                Ok(message) => chain.push(&message, serial),
+                Err(e) => {
+                    invalid = Some((i, format!("Message {} is invalid: {}", serial, e)));
+                    break;
+                }
+            }
+        }
+        if let Some((i, _)) = invalid {
+            lines.truncate(i);
+        }
+        if !lines.is_empty() {
+            storage.append(&lines)?;
+        }
+        next += lines.len() as u64;
+        if let Some((_, e)) = invalid {
+            return Err(e);
+        }
+    }
+    Ok(next - len)
+}
+
+/// Fetches a page of the messages `peer` has from serial `next` on, which is
+/// empty once there are none, checking that the message before them is
+/// `last`, as stored.
+pub(crate) async fn fetch_after(
+    peer: &Client,
+    next: u64,
+    last: Option<&str>,
+) -> Result<Vec<String>, String> {
+    // start at the last message there is to check the peer has it too
+    let from = next.saturating_sub(1);
+    let mut page = peer.lines_from(from).await?;
+    if page.is_empty() {
+        return Ok(Vec::new());
+    }
+    if from < next {
+        if last != Some(page[0].1.as_str()) {
+            return Err("Log isn't the start of the peer's".to_string());
+        }
+        page.remove(0);
+    }
+
+    let mut lines = Vec::with_capacity(page.len());
+    for ((serial, line), expected) in page.into_iter().zip(next..) {
+        if serial != expected {
+            return Err(format!("Peer sent serial {} for {}", serial, expected));
+        }
+        lines.push(line);
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod gossip_tests {
+    use std::time::Duration;
+
+    use crate::{
+        chain::Params,
+        client::Client,
+        difficulty::DifficultySchedule,
+        hash::Hash,
+        http::HTTP,
+        messages::NewBlock,
+        storage::Memory,
+        test_util::{tx, KEY2},
+    };
+
+    const RELAY_TOKEN: &str = "s3cret";
+
+    /// Starts a server with `log` and `peers`, returning a client for it.
+    fn node(log: Vec<String>, peers: Vec<String>) -> Client {
+        let params = Params {
+            difficulty: DifficultySchedule {
+                initial: 0,
+                ..DifficultySchedule::default()
+            },
+            ..Params::default()
+        };
+        let http = HTTP::new(
+            "127.0.0.1".to_string(),
+            "0".to_string(),
+            params,
+            None,
+            peers,
+            None,
+            vec![],
+        )
+        .with_relay_token(RELAY_TOKEN.to_string());
+        let (addr, server) = http.bind(Memory::new(log)).unwrap();
+        tokio::spawn(server);
+        Client::new(&format!("http://{}", addr))
+    }
+
+    /// The log of the server `client` talks to, once it has `len` messages.
+    async fn log_of(client: &Client, len: usize) -> Vec<String> {
+        for _ in 0..100 {
+            let lines = client.lines_from(0).await.unwrap();
+            if lines.len() >= len {
+                return lines.into_iter().map(|(_, line)| line).collect();
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("{} never got {} messages", client.base(), len);
+    }
+
+    #[tokio::test]
+    async fn test_relays_to_peers() {
+        let genesis = NewBlock::genesis().to_string();
+        let a = node(vec![genesis.clone()], vec![]);
+        let b = node(vec![genesis.clone()], vec![a.base().to_string()]);
+        let c = node(
+            vec![genesis.clone()],
+            vec![a.base().to_string(), b.base().to_string()],
+        );
+
+        // a gets it from both c and b, but keeps it once
+        c.post(&tx("Zm9v")).await.unwrap();
+        let expected = vec![genesis.clone(), tx("Zm9v").to_string()];
+        assert_eq!(log_of(&b, 2).await, expected);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(log_of(&a, 2).await, expected);
+
+        // relays are only taken from servers with the token
+        assert_eq!(
+            a.relay(&tx("YmF6")).await.err().unwrap(),
+            "Error: Missing or wrong relay token"
+        );
+        let wrong = a.clone().with_token("s3cre");
+        assert_eq!(
+            wrong.relay(&tx("YmF6")).await.err().unwrap(),
+            "Error: Missing or wrong relay token"
+        );
+
+        // and are checked like posts are
+        let peer = a.clone().with_token(RELAY_TOKEN);
+        let block = format!("block:v4:{}:1:{}:0:{}:", Hash::ZERO, Hash::ZERO, KEY2);
+        assert_eq!(
+            peer.relay(&block.parse().unwrap()).await.err().unwrap(),
+            "Error: Block's prev hash is not a known block"
+        );
+        assert_eq!(
+            a.post(&tx("Zm9v")).await.err().unwrap(),
+            "Error: Message is already in the log"
+        );
+        assert!(peer.relay(&tx("Zm9v")).await.is_ok());
+        assert_eq!(log_of(&a, 2).await, expected);
+
+        // a new node copies what its peer has
+        let d = node(vec![genesis.clone()], vec![c.base().to_string()]);
+        assert_eq!(log_of(&d, 2).await, expected);
+
+        // unless its log is a different one
+        let other = vec![genesis, tx("YmFy").to_string()];
+        let e = node(other.clone(), vec![c.base().to_string()]);
+        assert_eq!(log_of(&e, 2).await, other);
+    }
+
+    #[tokio::test]
+    async fn test_turns_away_messages_written_differently() {
+        let genesis = NewBlock::genesis().to_string();
+        let legacy = format!("{}.0", tx("Zm9v"));
+        let a = node(vec![genesis, legacy], vec![]);
+
+        let peer = a.clone().with_token(RELAY_TOKEN);
+        assert!(peer.relay(&tx("Zm9v")).await.is_ok());
+        assert_eq!(
+            a.post(&tx("Zm9v")).await.err().unwrap(),
+            "Error: Message is already in the log"
+        );
+        assert_eq!(a.log_len().await, Ok(2));
+    }
+
+    #[tokio::test]
+    async fn test_catch_up_stops_at_invalid_messages() {
+        let genesis = NewBlock::genesis().to_string();
+        let orphan = format!("block:v4:{}:1:{}:0:{}:", Hash::ZERO, Hash::ZERO, KEY2);
+        let log = vec![
+            genesis,
+            tx("Zm9v").to_string(),
+            orphan,
+            tx("YmFy").to_string(),
+        ];
+        let a = node(log.clone(), vec![]);
+
+        // what comes before the orphaned block is copied, but nothing after
+        let b = node(vec![log[0].clone()], vec![a.base().to_string()]);
+        assert_eq!(log_of(&b, 2).await, log[..2]);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(b.log_len().await, Ok(2));
+        assert_eq!(b.post(&tx("YmFy")).await, Ok(()));
+    }
+}
@@ -0,0 +1,100 @@
+use std::{fmt::Display, str::FromStr};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+
+/// A SHA-256 digest, written as 64 lowercase hex characters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Hash(pub [u8; 32]);
+
+impl Hash {
+    /// The all-zero hash, used where a message has nothing to refer to.
+    pub const ZERO: Hash = Hash([0; 32]);
+
+    pub fn of(bytes: &[u8]) -> Self {
+        Hash(Sha256::digest(bytes).into())
+    }
+
+    /// The number of leading zero bits, i.e. how hard the hash was to find.
+    pub fn leading_zeros(&self) -> u32 {
+        let mut zeros = 0;
+        for b in self.0 {
+            zeros += b.leading_zeros();
+            if b != 0 {
+                break;
+            }
+        }
+        zeros
+    }
+}
+
+impl Display for Hash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for b in self.0 {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Hash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // only lowercase, so every hash has exactly one spelling
+        let is_hex = |b: u8| b.is_ascii_digit() || (b'a'..=b'f').contains(&b);
+        if s.len() != 64 || !s.bytes().all(is_hex) {
+            return Err("Hash is not 64 lowercase hex characters".to_string());
+        }
+
+        let mut bytes = [0u8; 32];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).unwrap();
+        }
+        Ok(Hash(bytes))
+    }
+}
+
+// serialized in the same text form as everywhere else
+impl Serialize for Hash {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Hash {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        String::deserialize(d)?.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod hash_tests {
+    use super::Hash;
+
+    #[test]
+    fn test_hash_of_empty() {
+        assert_eq!(
+            Hash::of(b"").to_string(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn test_hash_leading_zeros() {
+        let mut bytes = [0xff; 32];
+        assert_eq!(Hash(bytes).leading_zeros(), 0);
+        bytes[0] = 0;
+        bytes[1] = 0x1f;
+        assert_eq!(Hash(bytes).leading_zeros(), 11);
+        assert_eq!(Hash::ZERO.leading_zeros(), 256);
+    }
+
+    #[test]
+    fn test_hash_round_trip() {
+        let h = Hash::of(b"racketchain");
+        assert_eq!(h.to_string().parse::<Hash>().unwrap(), h);
+        assert!(h.to_string().to_uppercase().parse::<Hash>().is_err());
+        assert!("abc".parse::<Hash>().is_err());
+    }
+}
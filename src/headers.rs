@@ -0,0 +1,86 @@
+//! Block headers for clients that don't need the transactions.
+//!
+//! A header is a block without its transactions. Blocks with a Merkle root
+//! are hashed by their header alone, so a client can check their proof of
+//! work and how they link up from headers only.
+
+use crate::{messages::NewBlockRef, storage::Storage};
+
+/// How many messages are read at a time.
+const BATCH: u64 = 1000;
+
+/// The headers of up to `limit` blocks from `from` on in the log, with their
+/// serials. Messages that aren't readable blocks are skipped.
+pub fn headers(
+    storage: &mut dyn Storage,
+    from: u64,
+    limit: usize,
+) -> Result<Vec<(u64, String)>, String> {
+    let len = storage.len()?;
+    let mut headers = Vec::new();
+    let mut start = from;
+    while start < len && headers.len() < limit {
+        let end = (start + BATCH).min(len);
+        for (serial, line) in (start..).zip(storage.range(start, end)?) {
+            if !line.starts_with("block:") {
+                continue;
+            }
+            if let Ok(block) = NewBlockRef::parse_lenient(&line) {
+                headers.push((serial, block.header()));
+            }
+            if headers.len() == limit {
+                break;
+            }
+        }
+        start = end;
+    }
+    Ok(headers)
+}
+
+#[cfg(test)]
+mod headers_tests {
+    use super::headers;
+    use crate::{
+        hash::Hash,
+        messages::NewBlock,
+        storage::Memory,
+        test_util::{KEY, KEY2, SIG},
+    };
+
+    #[test]
+    fn test_headers_skip_transactions() {
+        let tx = format!("transaction:Zm9v:{}:{}:{},1", SIG, KEY, KEY2);
+        let mut block = format!(
+            "block:v3:{}:100:7:{}:1;{}",
+            NewBlock::genesis().hash(),
+            KEY2,
+            tx.replace(':', ";")
+        )
+        .parse::<NewBlock>()
+        .unwrap();
+        block.merkle_root = Some(block.compute_merkle_root());
+        let mut log = Memory::new(vec![
+            NewBlock::genesis().to_string(),
+            tx.clone(),
+            block.to_string(),
+            "garbage".to_string(),
+            format!("block:2:{}:", KEY),
+        ]);
+
+        let all = headers(&mut log, 0, 200).unwrap();
+        assert_eq!(
+            all,
+            vec![
+                (0, format!("block:1337:{}", KEY)),
+                (2, block.header()),
+                (4, format!("block:2:{}", KEY)),
+            ]
+        );
+
+        // the header is all it takes to check the block's hash
+        assert_eq!(Hash::of(all[1].1.as_bytes()), block.hash());
+
+        assert_eq!(headers(&mut log, 1, 1).unwrap(), vec![all[1].clone()]);
+        assert!(headers(&mut log, 5, 200).unwrap().is_empty());
+    }
+}
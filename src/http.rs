@@ -2,20 +2,41 @@ use std::{
     net::SocketAddr,
     pin::Pin,
     str::FromStr,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
 
-use futures::Future;
-use hyper::{service::Service, Body, Request, Response, Server};
+use futures::{Future, FutureExt};
+use hyper::{
+    header::{self, HeaderValue},
+    server::conn::AddrStream,
+    service::Service,
+    Body, Method, Request, Response, Server,
+};
 use tokio::sync::Mutex;
+use tracing::{error, info, info_span, warn, Instrument};
 
 use crate::{
-    messages::{Message, NewMessage},
+    bans::{Bans, Target},
+    chain::{Chain, Params},
+    client::Client,
+    gossip, headers,
+    messages::NewMessage,
+    metrics::{ChainStats, Metrics, Rejection, TimedStorage},
+    snapshot,
+    storage::Storage,
     uor_opt, uor_res,
 };
 
-use redis::Commands;
+mod admin;
+mod tls;
+
+pub use admin::AdminToken;
+pub use tls::Tls;
 
 /// Represents a wrapper struct for the HTTP server that runs with the work queue.
 /// The server supports only one session at a time. For concurrency reasons.
@@ -23,28 +44,344 @@ pub struct HTTP {
     // the host and port for a http server
     host: String,
     port: String,
+    // the rules posted blocks are checked against
+    params: Params,
+    // how often to snapshot the chain while serving, if at all, on top of
+    // the snapshot taken on shutdown
+    snapshot_interval: Option<Duration>,
+    // the base urls of the servers to relay messages to
+    peers: Vec<String>,
+    // how to keep up with the server taking posts, if this one doesn't
+    replica: Option<Replica>,
+    // the tokens admin routes need, which are disabled without any
+    admin_tokens: Vec<AdminToken>,
+    // the token servers share to relay messages to each other, without which
+    // relays are turned away
+    relay_token: Option<String>,
+    // the certificate and key to serve over TLS with, if at all
+    tls: Option<Tls>,
+    // a PEM file with the certificate authorities to trust when talking to
+    // peers and the primary over https, instead of the usual ones
+    ca: Option<String>,
+    // how long requests get to finish once the server is shutting down
+    drain_timeout: Duration,
+}
+
+/// How long requests get to finish by default once the server is shutting
+/// down.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How a read-only server keeps up with the server taking posts.
+#[derive(Clone, Debug)]
+pub struct Replica {
+    /// The server to copy new messages from and forward posts to. Without
+    /// one, posts are turned away, and storage has to be kept up to date
+    /// some other way, like redis replication.
+    pub primary: Option<String>,
+    /// How often to check for new messages.
+    pub interval: Duration,
 }
 
 impl HTTP {
-    pub fn new(host: String, port: String) -> Self {
-        HTTP { host, port }
+    pub fn new(
+        host: String,
+        port: String,
+        params: Params,
+        snapshot_interval: Option<Duration>,
+        peers: Vec<String>,
+        replica: Option<Replica>,
+        admin_tokens: Vec<AdminToken>,
+    ) -> Self {
+        HTTP {
+            host,
+            port,
+            params,
+            snapshot_interval,
+            peers,
+            replica,
+            admin_tokens,
+            relay_token: None,
+            tls: None,
+            ca: None,
+            drain_timeout: DRAIN_TIMEOUT,
+        }
+    }
+
+    /// Serves over TLS instead of plain HTTP.
+    pub fn with_tls(mut self, tls: Tls) -> Self {
+        self.tls = Some(tls);
+        self
     }
 
+    /// Trusts only the certificate authorities in the PEM file at `ca` when
+    /// talking to peers and the primary over https.
+    pub fn with_ca(mut self, ca: String) -> Self {
+        self.ca = Some(ca);
+        self
+    }
+
+    /// Takes relays from servers that send `token`, and sends it along with
+    /// the messages relayed to peers.
+    pub fn with_relay_token(mut self, token: String) -> Self {
+        self.relay_token = Some(token);
+        self
+    }
+
+    /// Gives requests `timeout` to finish once the server is shutting down.
+    pub fn with_drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Serves until Ctrl-C or SIGTERM, then shuts down like
+    /// [`HTTP::bind_until`] does.
     pub async fn start(
         self,
-        redis: redis::Connection,
+        storage: impl Storage + Send + 'static,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let redirect_port = self.tls.as_ref().and_then(|tls| tls.redirect_port.clone());
+        let scheme = if self.tls.is_some() { "https" } else { "http" };
+        let host = self.host.clone();
+        let (addr, server) = self.bind_until(storage, shutdown_signal())?;
+
+        info!("Listening on {}://{}", scheme, addr);
+        if let Some(port) = redirect_port {
+            let from = SocketAddr::from_str(&format!("{}:{}", host, port))?;
+            let (from, redirect) = tls::redirect(from, addr.port())?;
+            info!("Redirecting http://{} to https", from);
+            tokio::spawn(redirect);
+        }
+
+        server.await?;
+        Ok(())
+    }
+
+    /// Gets the server ready to accept connections, returning the address it
+    /// listens on, which tells which port it got if asked for port 0, and
+    /// the future that serves it once it has caught up with its peers.
+    pub fn bind(
+        self,
+        storage: impl Storage + Send + 'static,
+    ) -> Result<
+        (SocketAddr, impl Future<Output = Result<(), hyper::Error>>),
+        Box<dyn std::error::Error + Send + Sync>,
+    > {
+        self.bind_until(storage, futures::future::pending())
+    }
+
+    /// Like [`HTTP::bind`], but the server shuts down once `shutdown`
+    /// resolves. It stops accepting connections, gives the requests it's in
+    /// the middle of the drain timeout to finish, and takes a last snapshot
+    /// of the chain, whether or not it takes them on an interval.
+    pub fn bind_until(
+        self,
+        mut storage: impl Storage + Send + 'static,
+        shutdown: impl Future<Output = ()> + Send + 'static,
+    ) -> Result<
+        (SocketAddr, impl Future<Output = Result<(), hyper::Error>>),
+        Box<dyn std::error::Error + Send + Sync>,
+    > {
         let addr = SocketAddr::from_str(&format!("{}:{}", self.host, self.port))?;
 
-        let server = Server::bind(&addr).serve(MakeSvc {
-            session: Arc::new(Session::create(redis)),
+        // rebuild the chain from the log before accepting new messages
+        let chain = snapshot::load_chain(&mut storage, self.params)?;
+        let bans = Bans::replay(storage.audit_log()?.iter().map(String::as_str));
+        let ca = self.ca.as_deref();
+        let peers = self
+            .peers
+            .iter()
+            .map(|peer| {
+                let client = Client::connect(peer, ca)?;
+                Ok(match &self.relay_token {
+                    Some(token) => client.with_token(token),
+                    None => client,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        let primary = self.replica.as_ref().and_then(|r| r.primary.as_deref());
+        let primary = primary.map(|p| Client::connect(p, ca)).transpose()?;
+        let metrics = Arc::new(Metrics::default());
+        let session = Arc::new(Session {
+            db: Mutex::new(Box::new(TimedStorage::new(storage, metrics.clone()))),
+            chain: Mutex::new(chain),
+            bans: Mutex::new(bans),
+            peers,
+            read_only: self.replica.is_some(),
+            primary,
+            admin_tokens: self.admin_tokens,
+            relay_token: self.relay_token,
+            metrics,
+            next_request: AtomicU64::new(0),
         });
+        if let Some(interval) = self.snapshot_interval {
+            tokio::spawn(take_snapshots(session.clone(), interval));
+        }
+
+        let make_svc = MakeSvc {
+            session: session.clone(),
+        };
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let stopped = async move {
+            let _ = stopped.await;
+        };
+        let (addr, server) = match &self.tls {
+            None => {
+                let server = Server::try_bind(&addr)?.serve(make_svc);
+                let addr = server.local_addr();
+                (addr, server.with_graceful_shutdown(stopped).boxed())
+            }
+            Some(tls) => {
+                let acceptor = tls::acceptor(tls)?;
+                let listener = std::net::TcpListener::bind(addr)?;
+                listener.set_nonblocking(true)?;
+                let addr = listener.local_addr()?;
+                let listener = tokio::net::TcpListener::from_std(listener)?;
+                let server = Server::builder(tls::incoming(listener, acceptor)).serve(make_svc);
+                (addr, server.with_graceful_shutdown(stopped).boxed())
+            }
+        };
+        let server = async move {
+            {
+                let mut db = session.db.lock().await;
+                let mut chain = session.chain.lock().await;
+                gossip::catch_up(&mut **db, &mut chain, &session.peers).await;
+            }
+            if let Some(replica) = self.replica {
+                tokio::spawn(follow(session.clone(), replica.interval));
+            }
+            tokio::pin!(server);
+            tokio::select! {
+                res = &mut server => return res,
+                _ = shutdown => {}
+            }
+
+            info!("Shutting down");
+            let _ = stop.send(());
+            let drained = match tokio::time::timeout(self.drain_timeout, &mut server).await {
+                Ok(res) => res,
+                Err(_) => {
+                    warn!(
+                        timeout = ?self.drain_timeout,
+                        "Gave up on requests still going"
+                    );
+                    Ok(())
+                }
+            };
+            let mut db = session.db.lock().await;
+            let chain = session.chain.lock().await;
+            if let Err(e) = snapshot::save(&mut **db, &chain) {
+                error!(error = %e, "Failed to save a snapshot");
+            }
+            drained
+        };
+        Ok((addr, server))
+    }
+}
 
-        println!("Listening on http://{}", addr);
+/// Resolves on Ctrl-C, or on SIGTERM where there is one.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        let mut signal = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM");
+        signal.recv().await;
+    };
+    #[cfg(not(unix))]
+    let terminate = futures::future::pending::<()>();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+}
 
-        server.await?;
-        Ok(())
+/// Saves a snapshot of the chain every `interval`, whenever there are new
+/// messages since the last one.
+async fn take_snapshots(session: Arc<Session>, interval: Duration) {
+    let mut saved = None;
+    loop {
+        tokio::time::sleep(interval).await;
+        let mut db = session.db.lock().await;
+        let chain = session.chain.lock().await;
+        if saved == Some(chain.log_len()) {
+            continue;
+        }
+        match snapshot::save(&mut **db, &chain) {
+            Ok(()) => saved = Some(chain.log_len()),
+            Err(e) => error!(error = %e, "Failed to save a snapshot"),
+        }
+    }
+}
+
+/// Keeps a read-only server up to date every `interval`, copying new
+/// messages from the primary into storage first if there is one.
+async fn follow(session: Arc<Session>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        if let Some(primary) = &session.primary {
+            if let Err(e) = copy_from_primary(&session, primary).await {
+                warn!(primary = primary.base(), error = %e, "Failed to copy from the primary");
+            }
+        }
+        if let Err(e) = read_new_messages(&session).await {
+            error!(error = %e, "Failed to read new messages");
+        }
+    }
+}
+
+/// Appends what the primary has past the end of storage. Storage isn't
+/// locked while waiting on the primary, since nothing else writes to it.
+async fn copy_from_primary(session: &Session, primary: &Client) -> Result<(), String> {
+    loop {
+        let (next, last) = {
+            let mut db = session.db.lock().await;
+            let len = db.len()?;
+            let last = match len {
+                0 => None,
+                len => db.range(len - 1, len)?.pop(),
+            };
+            (len, last)
+        };
+        let lines = gossip::fetch_after(primary, next, last.as_deref()).await?;
+        if lines.is_empty() {
+            return Ok(());
+        }
+        session.db.lock().await.append(&lines)?;
+    }
+}
+
+/// Replays the messages in storage the chain hasn't seen yet.
+async fn read_new_messages(session: &Session) -> Result<(), String> {
+    let mut db = session.db.lock().await;
+    let mut chain = session.chain.lock().await;
+    let len = db.len()?;
+    let lines = db.range(chain.log_len(), len)?;
+    chain.extend(lines.iter().map(String::as_str));
+    Ok(())
+}
+
+/// Appends `message` to the log and passes it on to the peers, unless it's
+/// already in the log. Returns whether it was appended, or why it wasn't and
+/// the error to respond with if it's invalid.
+async fn accept(session: &Session, message: &NewMessage) -> Result<bool, (Rejection, String, u16)> {
+    let mut db = session.db.lock().await;
+    let mut chain = session.chain.lock().await;
+    if chain.has_seen(&message.hash()) {
+        return Ok(false);
     }
+    let invalid = match message {
+        NewMessage::NewTransaction(_) => Rejection::InvalidTransaction,
+        NewMessage::NewBlock(_) => Rejection::InvalidBlock,
+    };
+    chain
+        .check(message)
+        .map_err(|e| (invalid, format!("Error: {}", e), 400))?;
+    let len = db.append(&[message.to_string()]).map_err(|_| {
+        let e = "Failed to push message to storage".to_string();
+        (Rejection::Storage, e, 500)
+    })?;
+    chain.push(message, len - 1);
+    gossip::relay(&session.peers, message);
+    Ok(true)
 }
 
 /// Represents a service for the hyper http server
@@ -52,6 +389,8 @@ struct Svc {
     // using a mutex to make sure not two sessions are running a container at the same time.
     // this might change if we want to design a more concurrent system.
     session: Arc<Session>,
+    // the address the connection comes from
+    remote: SocketAddr,
 }
 
 impl Service<Request<Body>> for Svc {
@@ -73,64 +412,281 @@ impl Service<Request<Body>> for Svc {
         fn mk_response(s: String) -> Result<Response<Body>, hyper::Error> {
             Ok(Response::builder().body(Body::from(s)).unwrap())
         }
+        // an error turning a message away, which is counted by reason
+        fn reject(
+            session: &Session,
+            reason: Rejection,
+            s: String,
+            code: u16,
+        ) -> Result<Response<Body>, hyper::Error> {
+            info!(status = code, reason = %s, "Rejected a message");
+            session.metrics.rejected(reason);
+            mk_error(s, code)
+        }
 
         let cloned_session = self.session.clone();
-        Box::pin(async move {
+        let remote = self.remote;
+        let metrics = self.session.metrics.clone();
+        let (method, route) = (req.method().clone(), route(req.method(), req.uri().path()));
+        let id = self.session.next_request.fetch_add(1, Ordering::Relaxed);
+        let span = info_span!(
+            "request",
+            id,
+            method = %req.method(),
+            path = %req.uri().path(),
+            remote = %remote,
+        );
+        let handled = async move {
             // routes
             // - GET:
             //   - /<id> -> get all messages since id
+            //   - /len -> get the number of messages
+            //   - /tip -> get the canonical tip as serial:hash:height:work
+            //   - /orphans -> get the blocks off the canonical branch, same format
+            //   - /mempool -> get the transactions not in a block, highest fee rate first
+            //   - /difficulty -> get the tip's difficulty and the next block's as current:next
+            //   - /headers?from=<serial> -> get the headers of the blocks from serial on as
+            //     serial:header, where a header is a block without its transactions
+            //   - /proof/<serial> -> get the canonical block header including a transaction as
+            //     serial:header, then the steps from the transaction to its Merkle root as
+            //     left:<hash> or right:<hash>, one per line
+            //   - /metrics -> get metrics in the Prometheus text format
+            //   - /admin/... -> see `admin`
             // - POST:
             //   - / -> post a message
-
+            //   - /relay -> pass on a message another server accepted, which is
+            //     ignored if it's already in the log, given the relay token as
+            //     `Authorization: Bearer <token>`
+            //   posts from banned addresses, and messages from banned senders, are turned away
+            //   read-only servers forward posts to their primary, or turn them away
 
             // get the method
             let method = req.method().to_string();
 
-            println!("Requested: {} -> {}", method, req.uri());
+            if req.uri().path().starts_with("/admin/") {
+                return Ok(admin::handle(&cloned_session, req).await);
+            }
             match method.as_str() {
                 "POST" => {
                     // post a message
-                    let body = uor_res!(hyper::body::to_bytes(req.body_mut()).await, || mk_error(
+                    let relayed = req.uri().path() == "/relay";
+                    if relayed && !relay_authorized(&req, &cloned_session) {
+                        return reject(
+                            &cloned_session,
+                            Rejection::Unauthorized,
+                            "Error: Missing or wrong relay token".to_string(),
+                            401,
+                        );
+                    }
+                    if cloned_session
+                        .bans
+                        .lock()
+                        .await
+                        .is_banned(&Target::Ip(remote.ip()))
+                    {
+                        return reject(
+                            &cloned_session,
+                            Rejection::Banned,
+                            "Error: Your address is banned".to_string(),
+                            403,
+                        );
+                    }
+                    let body = uor_res!(hyper::body::to_bytes(req.body_mut()).await, || reject(
+                        &cloned_session,
+                        Rejection::Malformed,
                         "Failed to read body".to_string(),
                         400
                     ));
-                    let message = uor_res!(String::from_utf8(body.to_vec()), || mk_error(
+                    let message = uor_res!(String::from_utf8(body.to_vec()), || reject(
+                        &cloned_session,
+                        Rejection::Malformed,
                         "Failed to parse body".to_string(),
                         400
                     ));
 
                     // return error if message has newlines
                     if message.contains('\n') {
-                        return mk_error("Error: Message contains newlines".to_string(), 400);
+                        return reject(
+                            &cloned_session,
+                            Rejection::Malformed,
+                            "Error: Message contains newlines".to_string(),
+                            400,
+                        );
                     }
 
                     let message = match NewMessage::from_str(&message) {
-                        Ok(m) => m.to_string(),
-                        Err(e) => return mk_error(format!("Error: {}", e), 400),
+                        Ok(m) => m,
+                        Err(e) => {
+                            return reject(
+                                &cloned_session,
+                                Rejection::Malformed,
+                                format!("Error: {}", e),
+                                400,
+                            )
+                        }
                     };
-
+                    let sender = match &message {
+                        NewMessage::NewTransaction(t) => &t.sender,
+                        NewMessage::NewBlock(b) => &b.miner_account,
+                    };
+                    if cloned_session
+                        .bans
+                        .lock()
+                        .await
+                        .is_banned(&Target::Key(sender.clone()))
                     {
-                        let mut redis = cloned_session.db.lock().await;
-
-                        uor_res!(
-                            redis::pipe()
-                                .atomic()
-                                .rpush("messages", message)
-                                .ignore()
-                                .query::<()>(&mut *redis),
-                            || mk_error("Failed to push message to redis".to_string(), 500)
+                        return reject(
+                            &cloned_session,
+                            Rejection::Banned,
+                            "Error: Sender is banned".to_string(),
+                            403,
                         );
                     }
 
-                    // sleep to rate limit
-                    tokio::time::sleep(std::time::Duration::from_millis(2000)).await;
+                    if cloned_session.read_only {
+                        let primary = match &cloned_session.primary {
+                            Some(primary) if !relayed => primary,
+                            _ => {
+                                return reject(
+                                    &cloned_session,
+                                    Rejection::ReadOnly,
+                                    "Error: This server is read-only".to_string(),
+                                    403,
+                                )
+                            }
+                        };
+                        return match primary.send(Method::POST, "/", message.to_string()).await {
+                            Ok((status, body)) => mk_error(body, status.as_u16()),
+                            Err(_) => {
+                                mk_error("Error: Failed to reach the primary".to_string(), 502)
+                            }
+                        };
+                    }
+
+                    match accept(&cloned_session, &message).await {
+                        Ok(true) => cloned_session.metrics.accepted(match message {
+                            NewMessage::NewTransaction(_) => "transaction",
+                            NewMessage::NewBlock(_) => "block",
+                        }),
+                        Ok(false) if relayed => return mk_response(String::new()),
+                        Ok(false) => {
+                            return reject(
+                                &cloned_session,
+                                Rejection::Duplicate,
+                                "Error: Message is already in the log".to_string(),
+                                400,
+                            )
+                        }
+                        Err((reason, e, code)) => return reject(&cloned_session, reason, e, code),
+                    }
+
+                    // sleep to rate limit, other than servers passing messages on
+                    if !relayed {
+                        tokio::time::sleep(std::time::Duration::from_millis(2000)).await;
+                    }
 
                     mk_response(String::new())
                 }
                 "GET" => {
-                    // get id from path
                     let path = req.uri().path().to_string();
-                    let id = uor_opt!(path.split('/').last(), || mk_error(
+                    match path.as_str() {
+                        "/len" => {
+                            let mut db = cloned_session.db.lock().await;
+                            let len = uor_res!(db.len(), || mk_error(
+                                "Failed to get messages from storage".to_string(),
+                                500
+                            ));
+                            return mk_response(format!("{}\n", len));
+                        }
+                        "/tip" => {
+                            let chain = cloned_session.chain.lock().await;
+                            let tip = uor_opt!(chain.tip_info(), || mk_error(
+                                "Error: There are no blocks".to_string(),
+                                404
+                            ));
+                            return mk_response(format!("{}\n", tip));
+                        }
+                        "/mempool" => {
+                            let chain = cloned_session.chain.lock().await;
+                            let mut buf = String::new();
+                            for (serial, t) in chain.mempool() {
+                                buf.push_str(&format!("{}:{}\n", serial, t));
+                            }
+                            return mk_response(buf);
+                        }
+                        "/difficulty" => {
+                            let chain = cloned_session.chain.lock().await;
+                            let (current, next) = chain.difficulty();
+                            return mk_response(format!("{}:{}\n", current, next));
+                        }
+                        "/headers" => {
+                            // from defaults to the start of the log
+                            let from = req
+                                .uri()
+                                .query()
+                                .unwrap_or("")
+                                .split('&')
+                                .find_map(|pair| pair.strip_prefix("from="))
+                                .unwrap_or("0");
+                            let from = uor_res!(from.parse::<u64>(), || mk_error(
+                                "Error: Failed to parse from".to_string(),
+                                400
+                            ));
+                            let res = {
+                                let mut db = cloned_session.db.lock().await;
+                                uor_res!(headers::headers(&mut **db, from, 200), || mk_error(
+                                    "Failed to get messages from storage".to_string(),
+                                    500
+                                ))
+                            };
+
+                            let mut buf = String::new();
+                            for (serial, header) in res {
+                                buf.push_str(&format!("{}:{}\n", serial, header));
+                            }
+                            return mk_response(buf);
+                        }
+                        "/metrics" => {
+                            let stats = {
+                                let chain = cloned_session.chain.lock().await;
+                                ChainStats {
+                                    log_len: chain.log_len(),
+                                    height: chain.tip_info().map(|tip| tip.height),
+                                    mempool: chain.mempool().len(),
+                                }
+                            };
+                            return mk_response(cloned_session.metrics.render(&stats));
+                        }
+                        "/orphans" => {
+                            let chain = cloned_session.chain.lock().await;
+                            let mut buf = String::new();
+                            for block in chain.orphans() {
+                                buf.push_str(&format!("{}\n", block));
+                            }
+                            return mk_response(buf);
+                        }
+                        _ => {}
+                    }
+
+                    if let Some(serial) = path.strip_prefix("/proof/") {
+                        let serial = uor_res!(serial.parse::<u64>(), || mk_error(
+                            "Error: Failed to parse serial".to_string(),
+                            400
+                        ));
+                        let chain = cloned_session.chain.lock().await;
+                        let (info, header, steps) = match chain.proof(serial) {
+                            Ok(proof) => proof,
+                            Err(e) => return mk_error(format!("Error: {}", e), 404),
+                        };
+                        let mut buf = format!("{}:{}\n", info.serial, header);
+                        for step in steps {
+                            buf.push_str(&format!("{}\n", step));
+                        }
+                        return mk_response(buf);
+                    }
+
+                    // get id from path
+                    let id = uor_opt!(path.split('/').next_back(), || mk_error(
                         "Failed to get id from path".to_string(),
                         400
                     ));
@@ -148,10 +704,10 @@ impl Service<Request<Body>> for Svc {
                     }
 
                     // get all messages since id
-                    let res: Vec<String> = {
-                        let mut redis = cloned_session.db.lock().await;
-                        uor_res!(redis.lrange("messages", id, id + 200), || mk_error(
-                            "Failed to get messages from redis".to_string(),
+                    let res = {
+                        let mut db = cloned_session.db.lock().await;
+                        uor_res!(db.range(id as u64, id as u64 + 201), || mk_error(
+                            "Failed to get messages from storage".to_string(),
                             500
                         ))
                     };
@@ -166,17 +722,62 @@ impl Service<Request<Body>> for Svc {
                 }
                 _ => mk_error(format!("ERROR: Invalid method {}", method), 500),
             }
-        })
+        };
+        Box::pin(
+            async move {
+                let start = Instant::now();
+                let mut res = handled.await;
+                if let Ok(res) = &mut res {
+                    let elapsed = start.elapsed();
+                    metrics.request(&method, route, res.status().as_u16(), elapsed);
+                    info!(status = res.status().as_u16(), ?elapsed, "Answered");
+                    res.headers_mut()
+                        .insert("x-request-id", HeaderValue::from(id));
+                }
+                res
+            }
+            .instrument(span),
+        )
     }
 }
 
-/// Represents a maker for a service for the hyper http server
+/// The route `path` is counted under in metrics, so that there's one per
+/// kind of request rather than one per serial.
+fn route(method: &Method, path: &str) -> &'static str {
+    match path {
+        "/" => "/",
+        "/relay" => "/relay",
+        "/len" => "/len",
+        "/tip" => "/tip",
+        "/orphans" => "/orphans",
+        "/mempool" => "/mempool",
+        "/difficulty" => "/difficulty",
+        "/headers" => "/headers",
+        "/metrics" => "/metrics",
+        _ if path.starts_with("/admin/") => "/admin",
+        _ if path.starts_with("/proof/") => "/proof",
+        _ if method == Method::GET => "/<id>",
+        _ => "other",
+    }
+}
 
+/// Represents a maker for a service for the hyper http server
 struct MakeSvc {
     session: Arc<Session>,
 }
 
-impl<T> Service<T> for MakeSvc {
+/// A connection, which knows the address it comes from.
+trait Remote {
+    fn remote_addr(&self) -> SocketAddr;
+}
+
+impl Remote for AddrStream {
+    fn remote_addr(&self) -> SocketAddr {
+        AddrStream::remote_addr(self)
+    }
+}
+
+impl<C: Remote> Service<&C> for MakeSvc {
     type Response = Svc;
     type Error = hyper::Error;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
@@ -185,22 +786,204 @@ impl<T> Service<T> for MakeSvc {
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, _: T) -> Self::Future {
+    fn call(&mut self, conn: &C) -> Self::Future {
         let session = self.session.clone();
-        let fut = async move { Ok(Svc { session }) };
+        let remote = conn.remote_addr();
+        let fut = async move { Ok(Svc { session, remote }) };
         Box::pin(fut)
     }
 }
 
+/// The token the request carries as `Authorization: Bearer <token>`.
+fn bearer(req: &Request<Body>) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// Compares without stopping at the first difference, so the time it takes
+/// doesn't tell how much of a guess was right.
+fn same_token(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Whether the request carries the token servers relay messages with.
+fn relay_authorized(req: &Request<Body>, session: &Session) -> bool {
+    match (&session.relay_token, bearer(req)) {
+        (Some(token), Some(given)) => same_token(given.as_bytes(), token.as_bytes()),
+        _ => false,
+    }
+}
+
 /// Represents the session being manipulated by the http server
 struct Session {
-    pub db: Mutex<redis::Connection>,
+    pub db: Mutex<Box<dyn Storage + Send>>,
+    // always locked after `db`, so the chain stays in step with the log
+    pub chain: Mutex<Chain>,
+    // the banned keys and addresses, locked after `db`
+    pub bans: Mutex<Bans>,
+    pub peers: Vec<Client>,
+    pub read_only: bool,
+    // the server a read-only one forwards posts to and copies messages from
+    pub primary: Option<Client>,
+    pub admin_tokens: Vec<AdminToken>,
+    pub relay_token: Option<String>,
+    pub metrics: Arc<Metrics>,
+    // the id to give the next request in logs
+    pub next_request: AtomicU64,
 }
 
-impl Session {
-    pub fn create(con: redis::Connection) -> Self {
-        Session {
-            db: Mutex::new(con),
+#[cfg(test)]
+mod http_tests {
+    use std::time::Duration;
+
+    use super::{Replica, HTTP};
+    use crate::{
+        chain::Params,
+        client::Client,
+        messages::NewBlock,
+        snapshot::Snapshot,
+        storage::Memory,
+        test_util::{serve, tx, Shared},
+    };
+
+    fn start_replica(primary: Option<String>) -> Client {
+        let replica = Replica {
+            primary,
+            interval: Duration::from_millis(50),
+        };
+        let http = HTTP::new(
+            "127.0.0.1".to_string(),
+            "0".to_string(),
+            Params::default(),
+            None,
+            vec![],
+            Some(replica),
+            vec![],
+        )
+        .with_relay_token("s3cret".to_string());
+        let storage = Memory::new(vec![NewBlock::genesis().to_string()]);
+        let (addr, server) = http.bind(storage).unwrap();
+        tokio::spawn(server);
+        Client::new(&format!("http://{}", addr))
+    }
+
+    #[tokio::test]
+    async fn test_read_only() {
+        let primary = serve(0);
+        let replica = start_replica(Some(primary.base().to_string()));
+
+        // posts go to the primary, and come back once the replica copies them
+        replica.post(&tx("Zm9v")).await.unwrap();
+        assert_eq!(primary.log_len().await, Ok(2));
+        for _ in 0..100 {
+            if replica.mempool().await.unwrap().len() == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(replica.lines_from(0).await, primary.lines_from(0).await);
+        assert_eq!(replica.mempool().await.unwrap()[0].serial, 1);
+
+        // along with the primary's reasons for turning them down
+        assert_eq!(
+            replica.post(&tx("Zm9v")).await.err().unwrap(),
+            "Error: Message is already in the log"
+        );
+        let peer = replica.clone().with_token("s3cret");
+        assert_eq!(
+            peer.relay(&tx("YmFy")).await.err().unwrap(),
+            "Error: This server is read-only"
+        );
+
+        let alone = start_replica(None);
+        assert_eq!(
+            alone.post(&tx("YmFy")).await.err().unwrap(),
+            "Error: This server is read-only"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shuts_down_gracefully() {
+        let http = HTTP::new(
+            "127.0.0.1".to_string(),
+            "0".to_string(),
+            Params::default(),
+            None,
+            vec![],
+            None,
+            vec![],
+        )
+        .with_drain_timeout(Duration::from_secs(10));
+        let storage = Memory::new(vec![NewBlock::genesis().to_string()]);
+        let (shutdown, signal) = tokio::sync::oneshot::channel::<()>();
+        let (addr, server) = http
+            .bind_until(storage, async move {
+                let _ = signal.await;
+            })
+            .unwrap();
+        let server = tokio::spawn(server);
+        let client = Client::new(&format!("http://{}", addr));
+
+        // a post that's under way when the server is told to stop still finishes
+        let post = tokio::spawn({
+            let client = client.clone();
+            async move { client.post(&tx("Zm9v")).await }
+        });
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        shutdown.send(()).unwrap();
+        assert!(post.await.unwrap().is_ok());
+        server.await.unwrap().unwrap();
+
+        // and nothing after it gets in
+        assert!(client.log_len().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_snapshots_on_shutdown() {
+        // without an interval, like --snapshot-interval 0
+        let http = HTTP::new(
+            "127.0.0.1".to_string(),
+            "0".to_string(),
+            Params::default(),
+            None,
+            vec![],
+            None,
+            vec![],
+        );
+        let storage = Shared::default();
+        storage
+            .lock()
+            .messages
+            .push(NewBlock::genesis().to_string());
+        let (shutdown, signal) = tokio::sync::oneshot::channel::<()>();
+        let (addr, server) = http
+            .bind_until(storage.clone(), async move {
+                let _ = signal.await;
+            })
+            .unwrap();
+        let server = tokio::spawn(server);
+        let client = Client::new(&format!("http://{}", addr));
+        client.post(&tx("Zm9v")).await.unwrap();
+        assert!(storage.lock().snapshot.is_none());
+
+        shutdown.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        let snapshot = storage.lock().snapshot.clone().unwrap();
+        assert_eq!(snapshot.parse::<Snapshot>().unwrap().len, 2);
+    }
+
+    #[tokio::test]
+    async fn test_request_ids() {
+        let client = serve(0);
+        let http = hyper::Client::new();
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let uri = format!("{}/len", client.base()).parse().unwrap();
+            let res = http.get(uri).await.unwrap();
+            ids.push(res.headers()["x-request-id"].to_str().unwrap().to_string());
         }
+        assert_eq!(ids, vec!["0", "1"]);
     }
 }
@@ -0,0 +1,309 @@
+//! Routes for running the server, under `/admin/`.
+//!
+//! They're only served when the server is given tokens, and only to
+//! requests carrying one as `Authorization: Bearer <token>`. Each token has
+//! a name, which the audit log records changes to the ban list under.
+
+use std::{fmt::Display, str::FromStr};
+
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+
+use super::{bearer, same_token, Session};
+use crate::{
+    bans::{Action, Entry},
+    chain::unix_now,
+    snapshot,
+};
+
+/// A token for admin routes, written as `<name>:<token>`, or just the token
+/// for one named `admin`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AdminToken {
+    pub name: String,
+    pub token: String,
+}
+
+impl Display for AdminToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.name, self.token)
+    }
+}
+
+impl FromStr for AdminToken {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, token) = s.split_once(':').unwrap_or(("admin", s));
+        if name.is_empty() || token.is_empty() {
+            return Err("Admin token must be <name>:<token> or <token>".to_string());
+        }
+        Ok(AdminToken {
+            name: name.to_string(),
+            token: token.to_string(),
+        })
+    }
+}
+
+/// The name of the token the request carries, if it's one of `tokens`.
+fn authorized<'a>(req: &Request<Body>, tokens: &'a [AdminToken]) -> Option<&'a str> {
+    let given = bearer(req)?;
+    tokens
+        .iter()
+        .find(|t| same_token(given.as_bytes(), t.token.as_bytes()))
+        .map(|t| t.name.as_str())
+}
+
+fn respond(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+// routes
+// - GET:
+//   - /admin/bans -> get the banned keys and addresses, one per line
+//   - /admin/audit -> get the audit log, one JSON entry per line
+// - POST:
+//   - /admin/snapshot -> save a snapshot of the chain now
+//   - /admin/ban -> ban the target in the body, given as key:<key> or ip:<address>,
+//     then a space and the reason
+//   - /admin/unban -> lift a ban, with the body given the same way
+pub(super) async fn handle(session: &Session, mut req: Request<Body>) -> Response<Body> {
+    if session.admin_tokens.is_empty() {
+        return respond(
+            StatusCode::NOT_FOUND,
+            "Error: Admin routes are disabled".to_string(),
+        );
+    }
+    let by = match authorized(&req, &session.admin_tokens) {
+        Some(name) => name.to_string(),
+        None => {
+            let mut res = respond(
+                StatusCode::UNAUTHORIZED,
+                "Error: Missing or wrong admin token".to_string(),
+            );
+            res.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                header::HeaderValue::from_static("Bearer"),
+            );
+            return res;
+        }
+    };
+
+    let path = req.uri().path().to_string();
+    match (req.method().clone(), path.as_str()) {
+        (Method::GET, "/admin/bans") => {
+            let bans = session.bans.lock().await;
+            let mut buf = String::new();
+            for target in bans.targets() {
+                buf.push_str(&format!("{}\n", target));
+            }
+            respond(StatusCode::OK, buf)
+        }
+        (Method::GET, "/admin/audit") => {
+            let mut db = session.db.lock().await;
+            match db.audit_log() {
+                Ok(log) => respond(
+                    StatusCode::OK,
+                    log.iter().map(|entry| format!("{}\n", entry)).collect(),
+                ),
+                Err(e) => respond(StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {}", e)),
+            }
+        }
+        (Method::POST, "/admin/snapshot") => {
+            let mut db = session.db.lock().await;
+            let chain = session.chain.lock().await;
+            match snapshot::save(&mut **db, &chain) {
+                Ok(()) => respond(
+                    StatusCode::OK,
+                    format!("Saved a snapshot of {} messages\n", chain.log_len()),
+                ),
+                Err(e) => respond(StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {}", e)),
+            }
+        }
+        (Method::POST, "/admin/ban" | "/admin/unban") => {
+            let action = match path.as_str() {
+                "/admin/ban" => Action::Ban,
+                _ => Action::Unban,
+            };
+            let body = match hyper::body::to_bytes(req.body_mut()).await {
+                Ok(body) => String::from_utf8_lossy(&body).trim().to_string(),
+                Err(_) => {
+                    return respond(StatusCode::BAD_REQUEST, "Failed to read body".to_string())
+                }
+            };
+            let (target, reason) = body.split_once(' ').unwrap_or((&body, ""));
+            let target = match target.parse() {
+                Ok(target) => target,
+                Err(e) => return respond(StatusCode::BAD_REQUEST, format!("Error: {}", e)),
+            };
+            let entry = Entry {
+                timestamp: unix_now(),
+                by,
+                action,
+                target,
+                reason: reason.trim().to_string(),
+            };
+            change_bans(session, entry).await
+        }
+        _ => respond(
+            StatusCode::NOT_FOUND,
+            "Error: No such admin route".to_string(),
+        ),
+    }
+}
+
+/// Records `entry` in the audit log, then applies it to the ban list, as
+/// long as it changes something.
+async fn change_bans(session: &Session, entry: Entry) -> Response<Body> {
+    let mut db = session.db.lock().await;
+    let mut bans = session.bans.lock().await;
+    let mut changed = bans.clone();
+    if let Err(e) = changed.apply(&entry) {
+        return respond(StatusCode::BAD_REQUEST, format!("Error: {}", e));
+    }
+    if let Err(e) = db.append_audit_log(&entry.to_string()) {
+        return respond(StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {}", e));
+    }
+    *bans = changed;
+    let done = match entry.action {
+        Action::Ban => "Banned",
+        Action::Unban => "Unbanned",
+    };
+    respond(StatusCode::OK, format!("{} {}\n", done, entry.target))
+}
+
+#[cfg(test)]
+mod admin_tests {
+    use hyper::{Method, StatusCode};
+
+    use super::AdminToken;
+    use crate::{
+        bans::Entry,
+        chain::Params,
+        client::Client,
+        http::HTTP,
+        messages::{NewBlock, NewMessage},
+        storage::Memory,
+        test_util::{tx, wallet, KEY2},
+        wallet::Wallet,
+    };
+
+    fn start(admin_tokens: &[&str], storage: Memory) -> Client {
+        let http = HTTP::new(
+            "127.0.0.1".to_string(),
+            "0".to_string(),
+            Params::default(),
+            None,
+            vec![],
+            None,
+            admin_tokens.iter().map(|t| t.parse().unwrap()).collect(),
+        );
+        let (addr, server) = http.bind(storage).unwrap();
+        tokio::spawn(server);
+        Client::new(&format!("http://{}", addr))
+    }
+
+    fn genesis() -> Memory {
+        Memory::new(vec![NewBlock::genesis().to_string()])
+    }
+
+    async fn call(client: &Client, method: Method, path: &str, body: &str) -> (StatusCode, String) {
+        client.send(method, path, body.to_string()).await.unwrap()
+    }
+
+    #[test]
+    fn test_token_format() {
+        let token = "alice:s3cret".parse::<AdminToken>().unwrap();
+        assert_eq!(
+            (token.name.as_str(), token.token.as_str()),
+            ("alice", "s3cret")
+        );
+        assert_eq!("s3cret".parse::<AdminToken>().unwrap().name, "admin");
+        assert!("alice:".parse::<AdminToken>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_needs_a_token() {
+        let client = start(&["s3cret", "bob:0th3r"], genesis());
+        let snapshot = "/admin/snapshot";
+
+        let (status, _) = call(&client, Method::POST, snapshot, "").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let wrong = client.clone().with_token("s3cre");
+        let (status, _) = call(&wrong, Method::POST, snapshot, "").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        for token in ["s3cret", "0th3r"] {
+            let admin = client.clone().with_token(token);
+            let (status, body) = call(&admin, Method::POST, snapshot, "").await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body, "Saved a snapshot of 1 messages\n");
+        }
+
+        // and without any, there's no way in
+        let client = start(&[], genesis()).with_token("");
+        let (status, _) = call(&client, Method::POST, snapshot, "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_bans() {
+        let wallet = Wallet::generate().unwrap();
+        let pay = || {
+            let moves = vec![format!("{},1", KEY2).parse().unwrap()];
+            NewMessage::NewTransaction(wallet.transaction(moves, Default::default()))
+        };
+        let client = start(&["alice:s3cret"], genesis());
+        let alice = client.clone().with_token("s3cret");
+
+        let ban = format!("key:{} flooding the log", wallet.account());
+        let (status, body) = call(&alice, Method::POST, "/admin/ban", &ban).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, format!("Banned key:{}\n", wallet.account()));
+        assert_eq!(
+            client.post(&pay()).await.err().unwrap(),
+            "Error: Sender is banned"
+        );
+        let (status, _) = call(&alice, Method::POST, "/admin/ban", &ban).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (_, body) = call(&alice, Method::GET, "/admin/bans", "").await;
+        assert_eq!(body, format!("key:{}\n", wallet.account()));
+        let (_, body) = call(&alice, Method::GET, "/admin/audit", "").await;
+        let entry = body.trim().parse::<Entry>().unwrap();
+        assert_eq!(
+            (entry.by.as_str(), entry.reason.as_str()),
+            ("alice", "flooding the log")
+        );
+
+        let unban = format!("key:{}", wallet.account());
+        let (status, _) = call(&alice, Method::POST, "/admin/unban", &unban).await;
+        assert_eq!(status, StatusCode::OK);
+        client.post(&pay()).await.unwrap();
+
+        let (status, _) = call(&alice, Method::POST, "/admin/ban", "ip:127.0.0.1").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            client.post(&pay()).await.err().unwrap(),
+            "Error: Your address is banned"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bans_outlast_restarts() {
+        let ban = format!(
+            r#"{{"timestamp":1,"by":"alice","action":"ban","target":{{"key":"{}"}},"reason":""}}"#,
+            wallet().account()
+        );
+        let mut storage = genesis();
+        storage.audit.push(ban);
+        let client = start(&[], storage);
+
+        assert_eq!(
+            client.post(&tx("Zm9v")).await.err().unwrap(),
+            "Error: Sender is banned"
+        );
+    }
+}
@@ -0,0 +1,369 @@
+//! Serving over TLS, and redirecting plain HTTP to it.
+
+use std::{
+    io::{self, BufReader},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+use futures::Future;
+use hyper::{
+    header,
+    server::accept::{self, Accept},
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use tokio::{net::TcpListener, sync::mpsc};
+use tokio_rustls::{rustls, server::TlsStream, TlsAcceptor};
+use tracing::{debug, warn};
+
+use super::Remote;
+
+/// How long a client gets to finish the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where to find the server's certificate and key, as PEM files.
+#[derive(Clone, Debug)]
+pub struct Tls {
+    /// The certificate chain, the server's own certificate first.
+    pub cert: String,
+    pub key: String,
+    /// A port to redirect plain HTTP from, if any.
+    pub redirect_port: Option<String>,
+}
+
+impl Remote for TlsStream<tokio::net::TcpStream> {
+    fn remote_addr(&self) -> SocketAddr {
+        // only fails once the connection is gone
+        self.get_ref()
+            .0
+            .peer_addr()
+            .unwrap_or_else(|_| SocketAddr::from(([0, 0, 0, 0], 0)))
+    }
+}
+
+/// Loads the certificate and key.
+pub(super) fn acceptor(tls: &Tls) -> Result<TlsAcceptor, String> {
+    let read = |path: &str| {
+        std::fs::File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| format!("Failed to open {}: {}", path, e))
+    };
+    let certs = rustls_pemfile::certs(&mut read(&tls.cert)?)
+        .map(|cert| cert.map(|cert| rustls::Certificate(cert.to_vec())))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Failed to read {}: {}", tls.cert, e))?;
+    if certs.is_empty() {
+        return Err(format!("{} has no certificates", tls.cert));
+    }
+    let key = rustls_pemfile::private_key(&mut read(&tls.key)?)
+        .map_err(|e| format!("Failed to read {}: {}", tls.key, e))?
+        .ok_or_else(|| format!("{} has no private key", tls.key))?;
+    let key = rustls::PrivateKey(key.secret_der().to_vec());
+
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("Certificate doesn't go with the key: {}", e))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// The connections made to `listener`, once their handshakes are done.
+/// Handshakes happen in the background, so a slow client doesn't hold up
+/// the others, and ones that fail are dropped.
+pub(super) fn incoming(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+) -> impl Accept<Conn = TlsStream<tokio::net::TcpStream>, Error = io::Error> {
+    let (tx, rx) = mpsc::channel(64);
+    tokio::spawn(async move {
+        loop {
+            let stream = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        warn!(error = %e, "Failed to accept a connection");
+                        continue;
+                    }
+                },
+                // the server is gone
+                _ = tx.closed() => return,
+            };
+            let (acceptor, tx) = (acceptor.clone(), tx.clone());
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        let _ = tx.send(stream).await;
+                    }
+                    Ok(Err(e)) => debug!(error = %e, "TLS handshake failed"),
+                    Err(_) => debug!("TLS handshake timed out"),
+                }
+            });
+        }
+    });
+    accept::from_stream(futures::stream::unfold(rx, |mut rx| async move {
+        let stream = rx.recv().await?;
+        Some((Ok(stream), rx))
+    }))
+}
+
+/// Serves permanent redirects to the same path over HTTPS on `https_port`,
+/// returning the address it listens on and the future that serves it.
+pub(super) fn redirect(
+    addr: SocketAddr,
+    https_port: u16,
+) -> Result<(SocketAddr, impl Future<Output = Result<(), hyper::Error>>), hyper::Error> {
+    let make_svc = make_service_fn(move |_| async move {
+        Ok::<_, hyper::Error>(service_fn(move |req| async move {
+            Ok::<_, hyper::Error>(redirect_to(&req, https_port))
+        }))
+    });
+    let server = Server::try_bind(&addr)?.serve(make_svc);
+    Ok((server.local_addr(), server))
+}
+
+fn redirect_to(req: &Request<Body>, https_port: u16) -> Response<Body> {
+    let host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<hyper::http::uri::Authority>().ok());
+    let host = match host {
+        Some(host) => host,
+        None => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from("Error: Missing Host header"))
+                .unwrap()
+        }
+    };
+    let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+    let location = match https_port {
+        443 => format!("https://{}{}", host.host(), path),
+        port => format!("https://{}:{}{}", host.host(), port, path),
+    };
+    // 308 rather than 301, so posts stay posts
+    Response::builder()
+        .status(StatusCode::PERMANENT_REDIRECT)
+        .header(header::LOCATION, location)
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tls_tests {
+    use std::sync::Arc;
+
+    use hyper::{client::conn, header, Body, Method, Request, StatusCode};
+    use tokio_rustls::{
+        rustls::{self, ServerName},
+        TlsConnector,
+    };
+
+    use super::{redirect, Tls};
+    use crate::{
+        chain::Params,
+        client::Client,
+        http::{Replica, HTTP},
+        messages::NewBlock,
+        storage::{Memory, Storage},
+        sync,
+        test_util::tx,
+    };
+
+    /// Writes a self-signed certificate for localhost and its key to a
+    /// temporary directory, returning their paths and the certificate.
+    fn self_signed(name: &str) -> (Tls, rustls::Certificate) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("tls-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+        std::fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+        let tls = Tls {
+            cert: cert_path.to_str().unwrap().to_string(),
+            key: key_path.to_str().unwrap().to_string(),
+            redirect_port: None,
+        };
+        (tls, rustls::Certificate(cert.cert.der().to_vec()))
+    }
+
+    async fn get(
+        addr: std::net::SocketAddr,
+        cert: rustls::Certificate,
+        path: &str,
+    ) -> Result<String, String> {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&cert).unwrap();
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), tcp)
+            .await
+            .map_err(|e| e.to_string())?;
+        let (mut sender, connection) = conn::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        let req = Request::get(path)
+            .header(header::HOST, "localhost")
+            .body(Body::empty())
+            .unwrap();
+        let res = sender.send_request(req).await.unwrap();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        Ok(String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_serves_over_tls() {
+        let (tls, cert) = self_signed("serve");
+        let http = HTTP::new(
+            "127.0.0.1".to_string(),
+            "0".to_string(),
+            Params::default(),
+            None,
+            vec![],
+            None,
+            vec![],
+        )
+        .with_tls(tls);
+        let storage = Memory::new(vec![NewBlock::genesis().to_string()]);
+        let (addr, server) = http.bind(storage).unwrap();
+        tokio::spawn(server);
+
+        assert_eq!(get(addr, cert, "/len").await.unwrap(), "1\n");
+
+        // a certificate it wasn't given isn't trusted
+        let (_, other) = self_signed("other");
+        assert!(get(addr, other, "/len").await.is_err());
+
+        // and plain HTTP gets nowhere
+        let plain = Client::new(&format!("http://{}", addr));
+        assert!(plain.log_len().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client_trusts_given_ca() {
+        let (tls, _) = self_signed("client");
+        let http = HTTP::new(
+            "127.0.0.1".to_string(),
+            "0".to_string(),
+            Params::default(),
+            None,
+            vec![],
+            None,
+            vec![],
+        )
+        .with_tls(tls.clone());
+        let storage = Memory::new(vec![NewBlock::genesis().to_string()]);
+        let (addr, server) = http.bind(storage).unwrap();
+        tokio::spawn(server);
+        let base = format!("https://localhost:{}", addr.port());
+
+        let client = Client::with_ca(&base, &tls.cert).unwrap();
+        assert_eq!(client.log_len().await, Ok(1));
+        client.post(&tx("Zm9v")).await.unwrap();
+        assert_eq!(client.log_len().await, Ok(2));
+
+        // a self-signed certificate isn't one of the usual authorities
+        assert!(Client::new(&base).log_len().await.is_err());
+        let (other, _) = self_signed("client-other");
+        let client = Client::with_ca(&base, &other.cert).unwrap();
+        assert!(client.log_len().await.is_err());
+        assert!(Client::with_ca(&base, &tls.key).is_err());
+    }
+
+    /// Waits for the server `client` talks to to have `len` messages.
+    async fn wait_for_len(client: &Client, len: u64) {
+        for _ in 0..100 {
+            if client.log_len().await == Ok(len) {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        panic!("{} never got {} messages", client.base(), len);
+    }
+
+    #[tokio::test]
+    async fn test_talks_to_servers_over_tls() {
+        let (tls, _) = self_signed("servers");
+        let genesis = || Memory::new(vec![NewBlock::genesis().to_string()]);
+        let server = |peers: Vec<String>, replica: Option<Replica>| {
+            HTTP::new(
+                "127.0.0.1".to_string(),
+                "0".to_string(),
+                Params::default(),
+                None,
+                peers,
+                replica,
+                vec![],
+            )
+            .with_relay_token("s3cret".to_string())
+        };
+        let (addr, primary) = server(vec![], None)
+            .with_tls(tls.clone())
+            .bind(genesis())
+            .unwrap();
+        tokio::spawn(primary);
+        let base = format!("https://localhost:{}", addr.port());
+        let primary = Client::with_ca(&base, &tls.cert).unwrap();
+
+        // peers relay to it
+        let (addr, peer) = server(vec![base.clone()], None)
+            .with_ca(tls.cert.clone())
+            .bind(genesis())
+            .unwrap();
+        tokio::spawn(peer);
+        let peer = Client::new(&format!("http://{}", addr));
+        peer.post(&tx("Zm9v")).await.unwrap();
+        wait_for_len(&primary, 2).await;
+
+        // replicas follow it and forward posts to it
+        let follow = Replica {
+            primary: Some(base.clone()),
+            interval: std::time::Duration::from_millis(50),
+        };
+        let (addr, replica) = server(vec![], Some(follow))
+            .with_ca(tls.cert.clone())
+            .bind(genesis())
+            .unwrap();
+        tokio::spawn(replica);
+        let replica = Client::new(&format!("http://{}", addr));
+        wait_for_len(&replica, 2).await;
+        replica.post(&tx("YmFy")).await.unwrap();
+        wait_for_len(&primary, 3).await;
+
+        // and its log can be synced
+        let mut copy = Memory::new(vec![]);
+        let copied = sync::sync(&mut copy, &primary, None, 2, &mut |_, _| {}).await;
+        assert_eq!(copied, Ok(3));
+        assert_eq!(copy.len(), Ok(3));
+
+        // but only with a CA that can be read
+        let bad = server(vec![base], None).with_ca(tls.key.clone());
+        assert!(bad.bind(genesis()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_redirects() {
+        let (addr, server) = redirect("127.0.0.1:0".parse().unwrap(), 8443).unwrap();
+        tokio::spawn(server);
+
+        let client = hyper::Client::new();
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(format!("http://{}/relay?x=1", addr))
+            .header(header::HOST, "example.com:8080")
+            .body(Body::from("message"))
+            .unwrap();
+        let res = client.request(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            res.headers()[header::LOCATION],
+            "https://example.com:8443/relay?x=1"
+        );
+    }
+}
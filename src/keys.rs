@@ -0,0 +1,111 @@
+//! Account keys and the signatures transactions carry.
+//!
+//! Accounts are named by 512-bit RSA public keys in the base64 wire format
+//! ssh uses (the middle part of an `ssh-rsa` line), and transactions are
+//! signed with PKCS#1 v1.5 over SHA-256.
+
+use rsa::{
+    pkcs1v15::{Signature, SigningKey, VerifyingKey},
+    sha2::Sha256,
+    signature::{SignatureEncoding, Signer, Verifier},
+    traits::PublicKeyParts,
+    BigUint, RsaPrivateKey, RsaPublicKey,
+};
+
+const SSH_RSA: &[u8] = b"ssh-rsa";
+
+/// Reads the next length-prefixed field of an ssh wire blob.
+fn read_field<'a>(blob: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = u32::from_be_bytes(blob.get(..4)?.try_into().ok()?) as usize;
+    let field = blob.get(4..4 + len)?;
+    *blob = &blob[4 + len..];
+    Some(field)
+}
+
+fn write_field(blob: &mut Vec<u8>, field: &[u8]) {
+    blob.extend_from_slice(&(field.len() as u32).to_be_bytes());
+    blob.extend_from_slice(field);
+}
+
+/// Writes a positive mpint, which needs a leading zero byte whenever its top
+/// bit is set.
+fn write_mpint(blob: &mut Vec<u8>, n: &BigUint) {
+    let mut bytes = n.to_bytes_be();
+    if bytes[0] & 0x80 != 0 {
+        bytes.insert(0, 0);
+    }
+    write_field(blob, &bytes);
+}
+
+pub fn parse_public_key(key: &str) -> Result<RsaPublicKey, String> {
+    let invalid = || "Key is not an ssh-rsa key".to_string();
+    let blob = base64::decode(key).map_err(|_| invalid())?;
+    let mut rest = blob.as_slice();
+    if read_field(&mut rest) != Some(SSH_RSA) {
+        return Err(invalid());
+    }
+    let e = read_field(&mut rest).ok_or_else(invalid)?;
+    let n = read_field(&mut rest).ok_or_else(invalid)?;
+    if !rest.is_empty() {
+        return Err(invalid());
+    }
+    RsaPublicKey::new(BigUint::from_bytes_be(n), BigUint::from_bytes_be(e)).map_err(|_| invalid())
+}
+
+pub fn encode_public_key(key: &RsaPublicKey) -> String {
+    let mut blob = Vec::new();
+    write_field(&mut blob, SSH_RSA);
+    write_mpint(&mut blob, key.e());
+    write_mpint(&mut blob, key.n());
+    base64::encode(blob)
+}
+
+/// Signs `payload`, giving the base64 signature a transaction carries.
+pub fn sign(key: &RsaPrivateKey, payload: &[u8]) -> String {
+    let signature = SigningKey::<Sha256>::new(key.clone()).sign(payload);
+    base64::encode(signature.to_bytes())
+}
+
+/// Checks that `sig` is `key`'s signature of `payload`.
+pub fn verify(key: &str, payload: &[u8], sig: &str) -> Result<(), String> {
+    let key = parse_public_key(key)?;
+    let sig = base64::decode(sig)
+        .ok()
+        .and_then(|sig| Signature::try_from(sig.as_slice()).ok())
+        .ok_or_else(|| "Signature is not base64".to_string())?;
+    VerifyingKey::<Sha256>::new(key)
+        .verify(payload, &sig)
+        .map_err(|_| "Signature doesn't match the sender's key".to_string())
+}
+
+#[cfg(test)]
+mod keys_tests {
+    use rsa::{rand_core::OsRng, RsaPrivateKey};
+
+    use super::{encode_public_key, parse_public_key, sign, verify};
+    use crate::test_util::{KEY, SIG};
+
+    #[test]
+    fn test_key_round_trip() {
+        let key = parse_public_key(KEY).unwrap();
+        assert_eq!(encode_public_key(&key), KEY);
+        assert!(parse_public_key("Zm9v").is_err());
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let private = RsaPrivateKey::new(&mut OsRng, 512).unwrap();
+        let public = encode_public_key(&private.to_public_key());
+        assert_eq!(public.len(), 116);
+
+        let sig = sign(&private, b"racketchain");
+        assert_eq!(sig.len(), 88);
+        assert!(verify(&public, b"racketchain", &sig).is_ok());
+        assert_eq!(
+            verify(&public, b"racketchains", &sig).err().unwrap(),
+            "Signature doesn't match the sender's key"
+        );
+        assert!(verify(KEY, b"racketchain", &sig).is_err());
+        assert!(verify(&public, b"racketchain", SIG).is_err());
+    }
+}
@@ -0,0 +1,241 @@
+//! Account balances, as of some block on the chain.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{amount::Amount, messages::NewBlock};
+
+/// How much a block's miner is rewarded, which halves every
+/// `halving_interval` blocks until it reaches zero.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RewardSchedule {
+    pub initial: Amount,
+    pub halving_interval: u64,
+}
+
+impl Default for RewardSchedule {
+    fn default() -> Self {
+        RewardSchedule {
+            initial: Amount::from_whole(50),
+            halving_interval: 1000,
+        }
+    }
+}
+
+impl RewardSchedule {
+    /// The reward for the block at `height`, where the genesis block is at 0.
+    pub fn reward(&self, height: u64) -> Amount {
+        let halvings = height / self.halving_interval.max(1);
+        let units = u32::try_from(halvings)
+            .ok()
+            .and_then(|h| self.initial.units().checked_shr(h))
+            .unwrap_or(0);
+        Amount::from_units(units)
+    }
+}
+
+/// The changes a block makes to a [`Ledger`], worked out before any of them
+/// are applied so a block either applies in full or not at all.
+pub struct LedgerUpdate {
+    balances: HashMap<String, Amount>,
+    included: Vec<u64>,
+}
+
+/// The balance of every account, and which transactions have been included
+/// in a block so far.
+///
+/// Miners are paid only through the reward schedule and the fees of the
+/// transactions they include: a block's transactions can move coins between
+/// accounts but never create them, so no sender may spend more than it has.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Ledger {
+    balances: HashMap<String, Amount>,
+    included: HashSet<u64>,
+}
+
+impl Ledger {
+    pub fn new() -> Self {
+        Ledger::default()
+    }
+
+    pub fn balance(&self, account: &str) -> Amount {
+        self.balances.get(account).copied().unwrap_or_default()
+    }
+
+    pub fn balances(&self) -> &HashMap<String, Amount> {
+        &self.balances
+    }
+
+    /// Whether the transaction at `serial` is already in a block.
+    pub fn is_included(&self, serial: u64) -> bool {
+        self.included.contains(&serial)
+    }
+
+    /// Works out what applying `block` would change, paying its miner
+    /// `reward` plus fees, or why the block breaks the rules.
+    pub fn check_block(&self, block: &NewBlock, reward: Amount) -> Result<LedgerUpdate, String> {
+        let mut balances = HashMap::new();
+        let mut included = Vec::with_capacity(block.transactions.len());
+        let credit = |balances: &mut HashMap<String, Amount>, account: &str, amount| {
+            let balance = balances
+                .entry(account.to_string())
+                .or_insert_with(|| self.balance(account));
+            *balance = balance
+                .checked_add(amount)
+                .ok_or_else(|| format!("Balance of {} overflows", account))?;
+            Ok::<_, String>(())
+        };
+
+        credit(&mut balances, &block.miner_account, reward)?;
+
+        for t in &block.transactions {
+            if self.is_included(t.serial) || included.contains(&t.serial) {
+                return Err(format!("Transaction {} is already in a block", t.serial));
+            }
+            included.push(t.serial);
+
+            let total = t
+                .moves
+                .iter()
+                .try_fold(t.fee, |total, m| total.checked_add(m.amount))
+                .ok_or_else(|| format!("Transaction {} moves too much", t.serial))?;
+            let balance = balances
+                .entry(t.sender.clone())
+                .or_insert_with(|| self.balance(&t.sender));
+            *balance = balance.checked_sub(total).ok_or_else(|| {
+                format!("Transaction {} spends more than its sender has", t.serial)
+            })?;
+
+            for m in &t.moves {
+                credit(&mut balances, &m.from, m.amount)?;
+            }
+            credit(&mut balances, &block.miner_account, t.fee)?;
+        }
+
+        Ok(LedgerUpdate { balances, included })
+    }
+
+    pub fn commit(&mut self, update: LedgerUpdate) {
+        self.balances.extend(update.balances);
+        self.included.extend(update.included);
+    }
+
+    pub fn apply_block(&mut self, block: &NewBlock, reward: Amount) -> Result<(), String> {
+        let update = self.check_block(block, reward)?;
+        self.commit(update);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod ledger_tests {
+    use super::{Ledger, RewardSchedule};
+    use crate::{
+        amount::Amount,
+        messages::NewBlock,
+        test_util::{KEY, KEY2, SIG},
+    };
+
+    fn block(miner: &str, transactions: &[String]) -> NewBlock {
+        format!("block:1:{}:{}", miner, transactions.join(":"))
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_fees_go_to_the_miner() {
+        let mut ledger = Ledger::new();
+        ledger
+            .apply_block(&block(KEY, &[]), Amount::from_whole(10))
+            .unwrap();
+
+        let b = block(KEY2, &[format!("{};fee,0.25", tx(1, KEY, KEY2, "2"))]);
+        ledger.apply_block(&b, Amount::ZERO).unwrap();
+        assert_eq!(ledger.balance(KEY).to_string(), "7.75");
+        assert_eq!(ledger.balance(KEY2).to_string(), "2.25");
+
+        // the fee counts towards what the sender spends
+        let b = block(KEY2, &[format!("{};fee,0.01", tx(2, KEY, KEY2, "7.75"))]);
+        assert_eq!(
+            ledger.apply_block(&b, Amount::ZERO).err().unwrap(),
+            "Transaction 2 spends more than its sender has"
+        );
+    }
+
+    fn tx(serial: u64, sender: &str, to: &str, amount: &str) -> String {
+        format!(
+            "{};transaction;Zm9v;{};{};{},{}",
+            serial, SIG, sender, to, amount
+        )
+    }
+
+    #[test]
+    fn test_reward_halves() {
+        let schedule = RewardSchedule {
+            initial: Amount::from_whole(50),
+            halving_interval: 10,
+        };
+        assert_eq!(schedule.reward(0), Amount::from_whole(50));
+        assert_eq!(schedule.reward(9), Amount::from_whole(50));
+        assert_eq!(schedule.reward(10), Amount::from_whole(25));
+        assert_eq!(schedule.reward(25).to_string(), "12.5");
+        assert_eq!(schedule.reward(10 * 64), Amount::ZERO);
+        assert_eq!(schedule.reward(u64::MAX), Amount::ZERO);
+
+        // the number of halvings doesn't wrap around
+        let schedule = RewardSchedule {
+            halving_interval: 1,
+            ..schedule
+        };
+        assert_eq!(schedule.reward(1 << 32), Amount::ZERO);
+        assert_eq!(schedule.reward((1 << 32) + 1), Amount::ZERO);
+    }
+
+    #[test]
+    fn test_apply_block_moves_coins() {
+        let mut ledger = Ledger::new();
+        ledger
+            .apply_block(&block(KEY, &[]), Amount::from_whole(50))
+            .unwrap();
+        assert_eq!(ledger.balance(KEY), Amount::from_whole(50));
+
+        let b = block(KEY2, &[tx(3, KEY, KEY2, "20.5")]);
+        ledger.apply_block(&b, Amount::from_whole(50)).unwrap();
+        assert_eq!(ledger.balance(KEY).to_string(), "29.5");
+        assert_eq!(ledger.balance(KEY2).to_string(), "70.5");
+        assert!(ledger.is_included(3));
+    }
+
+    #[test]
+    fn test_rejects_overspending_and_reuse() {
+        let mut ledger = Ledger::new();
+        ledger
+            .apply_block(&block(KEY, &[]), Amount::from_whole(10))
+            .unwrap();
+
+        // a miner can't mint coins for itself through a transaction
+        let b = block(KEY2, &[tx(1, KEY2, KEY2, "1000")]);
+        assert_eq!(
+            ledger
+                .apply_block(&b, Amount::from_whole(10))
+                .err()
+                .unwrap(),
+            "Transaction 1 spends more than its sender has"
+        );
+        assert_eq!(ledger.balance(KEY2), Amount::ZERO);
+
+        let b = block(KEY2, &[tx(1, KEY, KEY2, "6"), tx(1, KEY, KEY2, "1")]);
+        assert_eq!(
+            ledger.apply_block(&b, Amount::ZERO).err().unwrap(),
+            "Transaction 1 is already in a block"
+        );
+
+        let b = block(KEY2, &[tx(1, KEY, KEY2, "6"), tx(2, KEY, KEY2, "6")]);
+        assert!(ledger.apply_block(&b, Amount::ZERO).is_err());
+
+        // failed blocks leave the ledger untouched
+        assert_eq!(ledger.balance(KEY), Amount::from_whole(10));
+        assert!(!ledger.is_included(1));
+    }
+}
@@ -1,5 +1,27 @@
+pub mod amount;
+pub mod archive;
+pub mod bans;
+pub mod chain;
+pub mod client;
+pub mod difficulty;
+pub mod gossip;
+pub mod hash;
+pub mod headers;
 pub mod http;
+pub mod keys;
+pub mod ledger;
+pub mod merkle;
 pub mod messages;
+pub mod metrics;
+pub mod miner;
+pub mod snapshot;
+pub mod storage;
+pub mod sync;
+pub mod verify;
+pub mod wallet;
+
+#[cfg(test)]
+mod test_util;
 
 #[macro_export]
 macro_rules! uor_res {
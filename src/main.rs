@@ -1,29 +1,343 @@
-use racketchain_server::{http::HTTP, messages::{NewBlock}};
+use std::{
+    fs::File,
+    io::{self, Write},
+    str::FromStr,
+    time::Duration,
+};
+
+use clap::{Args, Parser, Subcommand};
+use racketchain_server::{
+    amount::Amount,
+    archive::{self, Format},
+    chain::Params,
+    client::Client,
+    difficulty::DifficultySchedule,
+    http::{AdminToken, Replica, Tls, HTTP},
+    ledger::RewardSchedule,
+    messages::NewBlock,
+    snapshot, sync, verify,
+};
+use tracing_subscriber::EnvFilter;
+
+/// Broadcasts racketchain messages over HTTP, storing them in redis.
+#[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    serve: ServeArgs,
+    /// What to log, as a level like debug, or directives like
+    /// racketchain_server::http=debug,info
+    #[arg(long, global = true, default_value = "info")]
+    log_level: String,
+    /// Log JSON lines instead of text
+    #[arg(long, global = true)]
+    log_json: bool,
+    /// A PEM file with the certificate authorities to trust when talking to
+    /// other servers over https, e.g. ones with self-signed certificates,
+    /// instead of the usual ones
+    #[arg(long, global = true)]
+    ca: Option<String>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Checks every message in redis against the rules, reporting the ones
+    /// that break them
+    Verify {
+        /// The redis server messages are stored in
+        #[arg(default_value = "redis://127.0.0.1/")]
+        redis_host: String,
+        #[command(flatten)]
+        params: ParamsArgs,
+    },
+    /// Writes every message in redis to an archive
+    Export {
+        /// The file to write to, or - for stdout
+        file: String,
+        /// The redis server messages are stored in
+        #[arg(default_value = "redis://127.0.0.1/")]
+        redis_host: String,
+        /// One of native, jsonl or csv
+        #[arg(long, default_value_t = Format::Native)]
+        format: Format,
+    },
+    /// Appends the messages in an archive to redis, after checking them
+    Import {
+        /// The file to read from, or - for stdin
+        file: String,
+        /// The redis server to store messages in
+        #[arg(default_value = "redis://127.0.0.1/")]
+        redis_host: String,
+        /// One of native, jsonl or csv
+        #[arg(long, default_value_t = Format::Native)]
+        format: Format,
+        /// Only check serials and hashes, not the rules, e.g. for messages
+        /// from before the rules existed
+        #[arg(long)]
+        unchecked: bool,
+        #[command(flatten)]
+        params: ParamsArgs,
+    },
+    /// Copies the messages another server has past the end of the log in
+    /// redis, picking up where an earlier sync stopped
+    Sync {
+        /// The server to copy from, e.g. http://10.0.0.2:8080
+        #[arg(long)]
+        from: String,
+        /// The redis server to store messages in
+        #[arg(default_value = "redis://127.0.0.1/")]
+        redis_host: String,
+        /// The number of pages of messages to download at once
+        #[arg(long, default_value_t = 4)]
+        connections: usize,
+        /// Only check serials, not the rules, e.g. for messages from before
+        /// the rules existed
+        #[arg(long)]
+        unchecked: bool,
+        #[command(flatten)]
+        params: ParamsArgs,
+    },
+    /// Saves a snapshot of the chain in redis now, for the server to pick up
+    /// from when it starts
+    Snapshot {
+        /// The redis server messages are stored in
+        #[arg(default_value = "redis://127.0.0.1/")]
+        redis_host: String,
+        #[command(flatten)]
+        params: ParamsArgs,
+    },
+}
+
+#[derive(Args)]
+struct ServeArgs {
+    /// The address to listen on
+    #[arg(required = true)]
+    host: Option<String>,
+    #[arg(required = true)]
+    port: Option<String>,
+    /// The redis server to store messages in
+    #[arg(default_value = "redis://127.0.0.1/")]
+    redis_host: String,
+    /// The number of seconds between snapshots of the chain, or 0 to only
+    /// take one on shutdown
+    #[arg(long, default_value_t = 600)]
+    snapshot_interval: u64,
+    /// Another server to pass accepted messages on to, e.g.
+    /// http://10.0.0.2:8080, which can be given more than once
+    #[arg(long = "peer")]
+    peers: Vec<String>,
+    /// A file holding the token servers share to relay messages to each
+    /// other, which peers need to be given as well. Relays are turned away
+    /// without one
+    #[arg(long)]
+    relay_token_file: Option<String>,
+    /// Serve what's in redis without taking posts, e.g. for a redis replica
+    #[arg(long)]
+    read_only: bool,
+    /// Follow this server, copying its messages into redis and forwarding
+    /// posts to it, which implies --read-only
+    #[arg(long)]
+    primary: Option<String>,
+    /// The number of seconds between checks for new messages when read-only
+    #[arg(long, default_value_t = 1)]
+    follow_interval: u64,
+    /// A file holding the tokens admin routes need, one per line as
+    /// <name>:<token>, or just a token named admin. Admin routes are
+    /// disabled without one
+    #[arg(long)]
+    admin_token_file: Option<String>,
+    /// Serve over TLS with the certificate chain in this PEM file
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<String>,
+    /// The PEM file holding the private key for --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<String>,
+    /// Also listen for plain HTTP on this port, redirecting it to HTTPS
+    #[arg(long, requires = "tls_cert")]
+    redirect_port: Option<String>,
+    /// The number of seconds requests get to finish on Ctrl-C or SIGTERM
+    #[arg(long, default_value_t = 30)]
+    drain_timeout: u64,
+    #[command(flatten)]
+    params: ParamsArgs,
+}
+
+/// The rules blocks are held to.
+#[derive(Args)]
+struct ParamsArgs {
+    /// The reward for mining a block, before any halvings
+    #[arg(long, default_value_t = RewardSchedule::default().initial)]
+    block_reward: Amount,
+    /// The number of blocks between each halving of the block reward
+    #[arg(long, default_value_t = RewardSchedule::default().halving_interval)]
+    halving_interval: u64,
+    /// The number of leading zero bits the first blocks' hashes need
+    #[arg(long, default_value_t = DifficultySchedule::default().initial)]
+    initial_difficulty: u32,
+    /// The number of blocks between each difficulty adjustment
+    #[arg(long, default_value_t = DifficultySchedule::default().retarget_interval)]
+    retarget_interval: u64,
+    /// The number of seconds difficulty adjustments aim for between blocks
+    #[arg(long, default_value_t = DifficultySchedule::default().target_block_time)]
+    target_block_time: u64,
+}
+
+impl ParamsArgs {
+    fn params(&self) -> Params {
+        Params {
+            reward: RewardSchedule {
+                initial: self.block_reward,
+                halving_interval: self.halving_interval,
+            },
+            difficulty: DifficultySchedule {
+                initial: self.initial_difficulty,
+                retarget_interval: self.retarget_interval,
+                target_block_time: self.target_block_time,
+            },
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() {
-    let args = std::env::args().collect::<Vec<_>>();
-
-    let host = args.get(1);
-    let port = args.get(2);
-    let redis_host = match args.get(3) {
-        Some(host) => host.to_string(),
-        None => "redis://127.0.0.1/".to_string(),
-    };
+    let cli = Cli::parse();
+    init_logging(&cli.log_level, cli.log_json);
 
-    let (host, port) = match (host, port) {
-        (Some(host), Some(port)) => (host, port),
-        _ => {
-            eprintln!("Usage: {} HOST PORT [redis-host, defaults to lo]", args[0]);
-            std::process::exit(1);
+    match cli.command {
+        Some(Command::Verify { redis_host, params }) => {
+            let mut con = connect(&redis_host);
+            run_verify(&mut con, params.params());
         }
-    };
+        Some(Command::Export {
+            file,
+            redis_host,
+            format,
+        }) => {
+            let mut con = connect(&redis_host);
+            run_export(&mut con, &file, format);
+        }
+        Some(Command::Import {
+            file,
+            redis_host,
+            format,
+            unchecked,
+            params,
+        }) => {
+            let mut con = connect(&redis_host);
+            let params = (!unchecked).then(|| params.params());
+            run_import(&mut con, &file, format, params);
+        }
+        Some(Command::Sync {
+            from,
+            redis_host,
+            connections,
+            unchecked,
+            params,
+        }) => {
+            let mut con = connect(&redis_host);
+            let params = (!unchecked).then(|| params.params());
+            run_sync(&mut con, &from, cli.ca.as_deref(), connections, params).await;
+        }
+        Some(Command::Snapshot { redis_host, params }) => {
+            let mut con = connect(&redis_host);
+            run_snapshot(&mut con, params.params());
+        }
+        None => {
+            let args = cli.serve;
+            let mut con = connect(&args.redis_host);
+            let read_only_redis = args.read_only && args.primary.is_none();
+            let snapshot_interval =
+                (args.snapshot_interval != 0).then(|| Duration::from_secs(args.snapshot_interval));
+            let admin_tokens = args
+                .admin_token_file
+                .as_deref()
+                .map(read_admin_tokens)
+                .unwrap_or_default();
+            let mut http = HTTP::new(
+                args.host.unwrap(),
+                args.port.unwrap(),
+                args.params.params(),
+                snapshot_interval,
+                args.peers,
+                (args.read_only || args.primary.is_some()).then(|| Replica {
+                    primary: args.primary,
+                    interval: Duration::from_secs(args.follow_interval),
+                }),
+                admin_tokens,
+            )
+            .with_drain_timeout(Duration::from_secs(args.drain_timeout));
+            if let Some(ca) = cli.ca {
+                http = http.with_ca(ca);
+            }
+            if let Some(file) = args.relay_token_file.as_deref() {
+                http = http.with_relay_token(read_relay_token(file));
+            }
+            if let (Some(cert), Some(key)) = (args.tls_cert, args.tls_key) {
+                http = http.with_tls(Tls {
+                    cert,
+                    key,
+                    redirect_port: args.redirect_port,
+                });
+            }
+            // a redis replica can't be written to, and has the genesis block already
+            if !read_only_redis {
+                run_migration_if_needed(&mut con);
+            }
+            http.start(con).await.expect("Failed to start http server");
+        }
+    }
+}
 
+/// Logs to stderr, leaving stdout to exports.
+fn init_logging(level: &str, json: bool) {
+    let filter = EnvFilter::try_new(level).unwrap_or_else(|e| {
+        eprintln!("Error: Log level is invalid: {}", e);
+        std::process::exit(1);
+    });
+    let logger = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr);
+    if json {
+        logger.json().init();
+    } else {
+        logger.init();
+    }
+}
+
+fn connect(redis_host: &str) -> redis::Connection {
     let client = redis::Client::open(redis_host).expect("Failed to connect to redis");
-    let mut con = client.get_connection().expect("Failed to get connection");
-    let http = HTTP::new(host.to_string(), port.to_string());
-    run_migration_if_needed(&mut con);
-    http.start(con).await.expect("Failed to start http server");
+    client.get_connection().expect("Failed to get connection")
+}
+
+fn read_admin_tokens(file: &str) -> Vec<AdminToken> {
+    let tokens = std::fs::read_to_string(file).expect("Failed to read admin token file");
+    let tokens = tokens
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(AdminToken::from_str)
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap_or_else(|e| {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        });
+    if tokens.is_empty() {
+        eprintln!("Error: Admin token file is empty");
+        std::process::exit(1);
+    }
+    tokens
+}
+
+fn read_relay_token(file: &str) -> String {
+    let token = std::fs::read_to_string(file).expect("Failed to read relay token file");
+    let token = token.trim();
+    if token.is_empty() {
+        eprintln!("Error: Relay token file is empty");
+        std::process::exit(1);
+    }
+    token.to_string()
 }
 
 /// Creates the genesis block if there are no messages in the database.
@@ -35,3 +349,107 @@ fn run_migration_if_needed(con: &mut redis::Connection) {
         let _: () = con.rpush("messages", genesis).unwrap();
     }
 }
+
+/// Prints every invalid message in the database, exiting with an error if
+/// there are any.
+fn run_verify(con: &mut redis::Connection, params: Params) {
+    use redis::Commands;
+    let messages: Vec<String> = con.lrange("messages", 0, -1).unwrap();
+    let (_, invalid) = verify::verify(params, messages.iter().map(String::as_str));
+    for entry in &invalid {
+        println!("{}", entry);
+    }
+    if !invalid.is_empty() {
+        eprintln!(
+            "{} of {} messages are invalid",
+            invalid.len(),
+            messages.len()
+        );
+        std::process::exit(1);
+    }
+    eprintln!("All {} messages are valid", messages.len());
+}
+
+/// Prints how many messages are done so far, over the previous count.
+fn show_progress(verb: &str) -> impl FnMut(u64, u64) + '_ {
+    move |done, total| {
+        eprint!("\r{} {}/{} messages", verb, done, total);
+        let _ = io::stderr().flush();
+        if done == total {
+            eprintln!();
+        }
+    }
+}
+
+fn run_export(con: &mut redis::Connection, file: &str, format: Format) {
+    let out: Box<dyn Write> = match file {
+        "-" => Box::new(io::stdout().lock()),
+        _ => Box::new(File::create(file).expect("Failed to create file")),
+    };
+    let result = archive::export(
+        con,
+        io::BufWriter::new(out),
+        format,
+        &mut show_progress("Exported"),
+    );
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run_import(con: &mut redis::Connection, file: &str, format: Format, params: Option<Params>) {
+    let input: Box<dyn io::Read> = match file {
+        "-" => Box::new(io::stdin().lock()),
+        _ => Box::new(File::open(file).expect("Failed to open file")),
+    };
+    let result = archive::read(input, format).and_then(|messages| {
+        archive::import(con, messages, params, &mut show_progress("Imported"))
+    });
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run_sync(
+    con: &mut redis::Connection,
+    from: &str,
+    ca: Option<&str>,
+    connections: usize,
+    params: Option<Params>,
+) {
+    let client = Client::connect(from, ca).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    });
+    let result = sync::sync(
+        con,
+        &client,
+        params,
+        connections,
+        &mut show_progress("Copied"),
+    )
+    .await;
+    match result {
+        Ok(copied) => eprintln!("Copied {} messages from {}", copied, from),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run_snapshot(con: &mut redis::Connection, params: Params) {
+    let result = snapshot::load_chain(con, params).and_then(|chain| {
+        snapshot::save(con, &chain)?;
+        Ok(chain.log_len())
+    });
+    match result {
+        Ok(len) => eprintln!("Saved a snapshot of {} messages", len),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,169 @@
+//! Merkle trees over a block's transactions.
+//!
+//! The leaves are the hashes of the transactions as the server serves them,
+//! i.e. `serial:transaction:...`, in the order the block lists them. Each
+//! node above is the SHA-256 hash of its children's bytes, left then right,
+//! and a node without a sibling is carried up a level as it is. The root of
+//! a block without transactions is the zero hash.
+
+use std::{fmt::Display, str::FromStr};
+
+use crate::hash::Hash;
+
+/// Which side of the node being proven a sibling is on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+/// One level of an inclusion proof, written as `left:<hash>` or
+/// `right:<hash>`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Step {
+    pub side: Side,
+    pub hash: Hash,
+}
+
+impl Display for Step {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.side {
+            Side::Left => write!(f, "left:{}", self.hash),
+            Side::Right => write!(f, "right:{}", self.hash),
+        }
+    }
+}
+
+impl FromStr for Step {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (side, hash) = s
+            .split_once(':')
+            .ok_or_else(|| "Step has less than two parts".to_string())?;
+        let side = match side {
+            "left" => Side::Left,
+            "right" => Side::Right,
+            _ => return Err("Step side is not left or right".to_string()),
+        };
+        Ok(Step {
+            side,
+            hash: hash.parse()?,
+        })
+    }
+}
+
+fn parent(left: &Hash, right: &Hash) -> Hash {
+    let mut bytes = [0u8; 64];
+    bytes[..32].copy_from_slice(&left.0);
+    bytes[32..].copy_from_slice(&right.0);
+    Hash::of(&bytes)
+}
+
+/// The level above `level`.
+fn next_level(level: &[Hash]) -> Vec<Hash> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => parent(left, right),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+pub fn root(leaves: &[Hash]) -> Hash {
+    if leaves.is_empty() {
+        return Hash::ZERO;
+    }
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level[0]
+}
+
+/// The steps from the leaf at `index` up to the root, if there is one.
+pub fn proof(leaves: &[Hash], mut index: usize) -> Option<Vec<Step>> {
+    if index >= leaves.len() {
+        return None;
+    }
+    let mut steps = Vec::new();
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        let sibling = index ^ 1;
+        if sibling < level.len() {
+            steps.push(Step {
+                side: if sibling < index {
+                    Side::Left
+                } else {
+                    Side::Right
+                },
+                hash: level[sibling],
+            });
+        }
+        level = next_level(&level);
+        index /= 2;
+    }
+    Some(steps)
+}
+
+/// The root that `steps` lead to from `leaf`, to compare with the root in a
+/// block's header.
+pub fn fold(leaf: Hash, steps: &[Step]) -> Hash {
+    steps.iter().fold(leaf, |node, step| match step.side {
+        Side::Left => parent(&step.hash, &node),
+        Side::Right => parent(&node, &step.hash),
+    })
+}
+
+#[cfg(test)]
+mod merkle_tests {
+    use super::{fold, proof, root, Side, Step};
+    use crate::hash::Hash;
+
+    fn leaves(n: u8) -> Vec<Hash> {
+        (0..n).map(|i| Hash::of(&[i])).collect()
+    }
+
+    #[test]
+    fn test_root() {
+        assert_eq!(root(&[]), Hash::ZERO);
+        let l = leaves(3);
+        assert_eq!(root(&l[..1]), l[0]);
+
+        let mut pair = [0u8; 64];
+        pair[..32].copy_from_slice(&l[0].0);
+        pair[32..].copy_from_slice(&l[1].0);
+        let left = Hash::of(&pair);
+        assert_eq!(root(&l[..2]), left);
+
+        // the odd leaf is carried up as it is
+        pair[..32].copy_from_slice(&left.0);
+        pair[32..].copy_from_slice(&l[2].0);
+        assert_eq!(root(&l), Hash::of(&pair));
+    }
+
+    #[test]
+    fn test_every_leaf_proves_the_root() {
+        for n in 1..=9 {
+            let l = leaves(n);
+            for (i, leaf) in l.iter().enumerate() {
+                let steps = proof(&l, i).unwrap();
+                assert_eq!(fold(*leaf, &steps), root(&l));
+                assert_ne!(fold(Hash::ZERO, &steps), root(&l));
+            }
+            assert!(proof(&l, n as usize).is_none());
+        }
+    }
+
+    #[test]
+    fn test_step_round_trip() {
+        let step = Step {
+            side: Side::Left,
+            hash: Hash::of(b"racketchain"),
+        };
+        assert_eq!(step.to_string().parse::<Step>().unwrap(), step);
+        assert!("up:00".parse::<Step>().is_err());
+    }
+}
@@ -1,91 +1,105 @@
 use std::{fmt::Display, str::FromStr};
 
+use crate::{amount::Amount, hash::Hash};
+
+mod canonical;
+mod refs;
+
+pub use refs::{
+    BlockRef, MessageRef, MoveRef, NewBlockRef, NewMessageRef, NewTransactionRef, TransactionRef,
+};
+
+#[derive(Clone, Debug)]
 pub struct Move {
     pub from: String,
-    pub amount: f64,
+    pub amount: Amount,
 }
 
 impl FromStr for Move {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let split = s.split(',').collect::<Vec<&str>>();
-        if split.len() != 2 {
-            return Err("Move has more than two parts".to_string());
-        }
-        let from = split.get(0).unwrap().to_string();
-        // check it's actually base64
-        let _ = base64::decode(&from)
-            .map_err(|_| format!("Recipient public key ({}) is not base64", from))?;
-
-        // check it's right length
-        if from.len() != 116 {
-            return Err(format!("Recipient public key ({}) is an invalid key", from));
-        }
-
-        let amount = split.get(1).unwrap().to_string();
-        let amount = match amount.parse::<f64>() {
-            Ok(x) => x,
-            Err(_) => return Err("Amount is not a number".to_string()),
-        };
-
-        // check that the amount is positive and not too big
-
-        if amount <= 0.0 {
-            return Err("Amount must be positive".to_string());
-        }
-
-        if amount == f64::INFINITY {
-            return Err("Amount is too big".to_string());
-        }
-
-        Ok(Move { from, amount })
+        MoveRef::parse(s).map(MoveRef::into_owned)
     }
 }
 
 impl Display for Move {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        // if the amount = Infinity, then we need to print float max value
-        let amount = if self.amount == f64::INFINITY {
-            f64::MAX
-        } else {
-            self.amount
-        };
-
-        write!(f, "{},{}", self.from, amount)
+        write!(f, "{},{}", self.from, self.amount)
     }
 }
 
+#[derive(Clone, Debug)]
 pub struct Transaction {
     pub serial: u64,
     pub unique_string: String,
     pub sig: String,
     pub sender: String,
     pub moves: Vec<Move>,
+    /// Paid to the miner of the block that includes the transaction. Zero
+    /// for transactions written before fees existed.
+    pub fee: Amount,
 }
 
+#[derive(Clone, Debug)]
 pub struct NewTransaction {
     pub unique_string: String,
     pub sig: String,
     pub sender: String,
     pub moves: Vec<Move>,
+    /// Paid to the miner of the block that includes the transaction. Zero
+    /// for transactions written before fees existed.
+    pub fee: Amount,
 }
 
-impl Transaction {
-    fn help_fmt(&self, f: &mut std::fmt::Formatter<'_>, sep: &str) -> std::fmt::Result {
-        write!(f, "{}{}", self.serial, sep)?;
-        write!(f, "transaction{}", sep)?;
-        write!(f, "{}{}", self.unique_string, sep)?;
-        write!(f, "{}{}", self.sig, sep)?;
-        write!(f, "{}{}", self.sender, sep)?;
-        let num_moves = self.moves.len();
-        for (i, m) in self.moves.iter().enumerate() {
-            write!(f, "{}", m)?;
-            if i != num_moves - 1 {
-                write!(f, "{}", sep)?;
-            }
+/// Writes the fields shared by posted and stored transactions, i.e.
+/// everything but the serial.
+fn write_transaction<W: std::fmt::Write>(
+    w: &mut W,
+    sep: &str,
+    unique_string: &str,
+    sig: &str,
+    sender: &str,
+    moves: &[Move],
+    fee: Amount,
+) -> std::fmt::Result {
+    write!(w, "transaction{}", sep)?;
+    write!(w, "{}{}", unique_string, sep)?;
+    write!(w, "{}{}", sig, sep)?;
+    write!(w, "{}{}", sender, sep)?;
+    let num_moves = moves.len();
+    for (i, m) in moves.iter().enumerate() {
+        write!(w, "{}", m)?;
+        if i != num_moves - 1 {
+            write!(w, "{}", sep)?;
         }
-        Ok(())
+    }
+    if !fee.is_zero() {
+        if num_moves != 0 {
+            write!(w, "{}", sep)?;
+        }
+        write!(w, "{}{}", refs::FEE_PREFIX, fee)?;
+    }
+    Ok(())
+}
+
+impl Transaction {
+    fn help_fmt<W: std::fmt::Write>(&self, w: &mut W, sep: &str) -> std::fmt::Result {
+        write!(w, "{}{}", self.serial, sep)?;
+        self.write_body(w, sep)
+    }
+
+    /// Writes the transaction the way it was posted, without its serial.
+    fn write_body<W: std::fmt::Write>(&self, w: &mut W, sep: &str) -> std::fmt::Result {
+        write_transaction(
+            w,
+            sep,
+            &self.unique_string,
+            &self.sig,
+            &self.sender,
+            &self.moves,
+            self.fee,
+        )
     }
 }
 
@@ -97,18 +111,15 @@ impl Display for Transaction {
 
 impl Display for NewTransaction {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "transaction:")?;
-        write!(f, "{}:", self.unique_string)?;
-        write!(f, "{}:", self.sig)?;
-        write!(f, "{}:", self.sender)?;
-        let num_moves = self.moves.len();
-        for (i, m) in self.moves.iter().enumerate() {
-            write!(f, "{}", m)?;
-            if i != num_moves - 1 {
-                write!(f, ":")?;
-            }
-        }
-        Ok(())
+        write_transaction(
+            f,
+            ":",
+            &self.unique_string,
+            &self.sig,
+            &self.sender,
+            &self.moves,
+            self.fee,
+        )
     }
 }
 
@@ -116,63 +127,7 @@ impl FromStr for Transaction {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let split = s.split(':').collect::<Vec<&str>>();
-        if split.len() < 5 {
-            return Err("Transaction has less than five parts".to_string());
-        }
-
-        let serial = split.get(0).unwrap().to_string();
-        let serial = match serial.parse::<u64>() {
-            Ok(x) => x,
-            Err(_) => return Err("Serial is not a number".to_string()),
-        };
-
-        // check second is transaction
-        if split.get(1).unwrap() != &"transaction" {
-            return Err("Second part is not transaction".to_string());
-        }
-
-        let unique_string = split.get(2).unwrap().to_string();
-        // check it's actually base64
-        let _ = base64::decode(&unique_string)
-            .map_err(|_| "Unique string is not base64".to_string())?;
-
-        // check it's at least 1 char
-        if unique_string.is_empty() {
-            return Err("Unique string is too short".to_string());
-        }
-
-        let sig = split.get(3).unwrap().to_string();
-        // check it's actually base64
-        let _ = base64::decode(&sig).map_err(|_| "Signature is not base64".to_string())?;
-
-        // check it's right length
-        if sig.len() != 88 {
-            return Err("Signature has an invalid length".to_string());
-        }
-
-        let sender = split.get(4).unwrap().to_string();
-        // check it's actually base64
-        let _ = base64::decode(&sender)
-            .map_err(|_| format!("Sender public key ({}) is not base64", sender))?;
-
-        // check it's right length
-        if sender.len() != 116 {
-            return Err(format!("Sender public key ({}) is an invalid key", sender));
-        }
-
-        let moves = split.get(5..).unwrap().to_vec();
-        let moves = moves
-            .iter()
-            .map(|x| x.parse::<Move>())
-            .collect::<Result<Vec<Move>, String>>()?;
-        Ok(Transaction {
-            unique_string,
-            serial,
-            sig,
-            sender,
-            moves,
-        })
+        TransactionRef::parse(s).map(TransactionRef::into_owned)
     }
 }
 
@@ -180,77 +135,46 @@ impl FromStr for NewTransaction {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let split = s.split(':').collect::<Vec<&str>>();
-        if split.len() < 4 {
-            return Err("Transaction has less than four parts".to_string());
-        }
-
-        // check first is transaction
-        if split.get(0).unwrap() != &"transaction" {
-            return Err("First part is not transaction".to_string());
-        }
-
-        let unique_string = split.get(1).unwrap().to_string();
-        // check it's actually base64
-        let _ = base64::decode(&unique_string)
-            .map_err(|_| "Unique string is not base64".to_string())?;
-
-        // check it's at least 1 char
-        if unique_string.is_empty() {
-            return Err("Unique string is too short".to_string());
-        }
-
-        let sig = split.get(2).unwrap().to_string();
-        // check it's actually base64
-        let _ = base64::decode(&sig).map_err(|_| "Signature is not base64".to_string())?;
-
-        // check it's right length
-        if sig.len() != 88 {
-            return Err("Signature has an invalid length".to_string());
-        }
-
-        let sender = split.get(3).unwrap().to_string();
-        // check it's actually base64
-        let _ = base64::decode(&sender)
-            .map_err(|_| format!("Sender public key ({}) is not base64", sender))?;
-
-        // check it's right length
-        if sender.len() != 116 {
-            return Err(format!("Sender public key ({}) is an invalid key", sender));
-        }
-
-        let moves = split.get(4..).unwrap().to_vec();
-        let moves = moves
-            .iter()
-            .map(|x| x.parse::<Move>())
-            .collect::<Result<Vec<Move>, String>>()?;
-        Ok(NewTransaction {
-            unique_string,
-            sig,
-            sender,
-            moves,
-        })
+        NewTransactionRef::parse(s).map(NewTransactionRef::into_owned)
     }
 }
 
+#[derive(Clone, Debug)]
 pub struct NewBlock {
+    /// The hash of the block this one extends. Blocks written before the
+    /// chain was linked by hash don't have one.
+    pub prev_hash: Option<Hash>,
+    /// When the block was mined, in seconds since the Unix epoch. Blocks
+    /// written before blocks were timestamped don't have one.
+    pub timestamp: Option<u64>,
+    /// The root of the Merkle tree over the transactions, see
+    /// [`crate::merkle`]. Blocks written before their hash covered only the
+    /// header don't have one.
+    pub merkle_root: Option<Hash>,
     pub transactions: Vec<Transaction>,
-    pub nonce: f64,
+    pub nonce: u64,
     pub miner_account: String,
 }
 
+#[derive(Clone, Debug)]
 pub struct Block {
     pub serial: u64,
+    pub prev_hash: Option<Hash>,
+    pub timestamp: Option<u64>,
+    pub merkle_root: Option<Hash>,
     pub transactions: Vec<Transaction>,
-    pub nonce: f64,
+    pub nonce: u64,
     pub miner_account: String,
 }
 
 impl NewBlock {
     pub fn genesis() -> Self {
         NewBlock {
+            prev_hash: None,
+            timestamp: None,
+            merkle_root: None,
             transactions: vec![],
-            nonce: 1337.0,
+            nonce: 1337,
             miner_account: format!(
                 "{}{}",
                 "AAAAB3NzaC1yc2EAAAADAQABAAAAQQDbXz4rfbrRrXYQJbwuC",
@@ -264,50 +188,7 @@ impl FromStr for Block {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let split = s.split(':').collect::<Vec<&str>>();
-        if split.len() < 6 {
-            return Err("Block has less than six parts".to_string());
-        }
-
-        let serial = split.get(0).unwrap().to_string();
-        let serial = match serial.parse::<u64>() {
-            Ok(x) => x,
-            Err(_) => return Err("Serial is not a number".to_string()),
-        };
-
-        // check second is block
-        if split.get(1).unwrap() != &"block" {
-            return Err("Second part is not block".to_string());
-        }
-
-        let nonce = split.get(2).unwrap().to_string();
-        let nonce = match nonce.parse::<f64>() {
-            Ok(x) => x,
-            Err(_) => return Err("Nonce is not a number".to_string()),
-        };
-
-        let miner_account = split.get(3).unwrap().to_string();
-        // check it's actually base64
-        let _ = base64::decode(&miner_account)
-            .map_err(|_| "Miner account is not base64".to_string())?;
-
-        // check it's right length
-        if miner_account.len() != 116 {
-            return Err("Miner account is an invalid key".to_string());
-        }
-
-        let transactions = split.get(4..).unwrap().to_vec();
-        let transactions = transactions
-            .iter()
-            .map(|x| x.replace(';', ":").parse::<Transaction>())
-            .collect::<Result<Vec<Transaction>, String>>()?;
-
-        Ok(Block {
-            serial,
-            transactions,
-            nonce,
-            miner_account,
-        })
+        BlockRef::parse(s).map(BlockRef::into_owned)
     }
 }
 
@@ -315,89 +196,124 @@ impl FromStr for NewBlock {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let split = s.split(':').collect::<Vec<&str>>();
-        if split.len() < 4 {
-            return Err("Block has less than five parts".to_string());
-        }
-
-        // check second is block
-        if split.get(0).unwrap() != &"block" {
-            return Err("Second part is not block".to_string());
-        }
-
-        let nonce = split.get(1).unwrap().to_string();
-        let nonce = match nonce.parse::<f64>() {
-            Ok(x) => x,
-            Err(_) => return Err("Nonce is not a number".to_string()),
-        };
+        NewBlockRef::parse(s).map(NewBlockRef::into_owned)
+    }
+}
 
-        let miner_account = split.get(2).unwrap().to_string();
-        // check it's actually base64
-        let _ = base64::decode(&miner_account)
-            .map_err(|_| "Miner account is not base64".to_string())?;
+/// Writes a block's header, i.e. everything before its transactions.
+fn write_header<W: std::fmt::Write>(
+    w: &mut W,
+    prev_hash: Option<Hash>,
+    timestamp: Option<u64>,
+    merkle_root: Option<Hash>,
+    nonce: u64,
+    miner_account: &str,
+) -> std::fmt::Result {
+    write!(w, "block:")?;
+    // a timestamped block with nothing before it refers to the zero hash
+    match (prev_hash, timestamp, merkle_root) {
+        (prev_hash, timestamp, Some(merkle_root)) => write!(
+            w,
+            "{}:{}:{}:{}:",
+            refs::BLOCK_V4,
+            prev_hash.unwrap_or(Hash::ZERO),
+            timestamp.unwrap_or(0),
+            merkle_root
+        )?,
+        (prev_hash, Some(timestamp), None) => write!(
+            w,
+            "{}:{}:{}:",
+            refs::BLOCK_V3,
+            prev_hash.unwrap_or(Hash::ZERO),
+            timestamp
+        )?,
+        (Some(prev_hash), None, None) => write!(w, "{}:{}:", refs::BLOCK_V2, prev_hash)?,
+        (None, None, None) => {}
+    }
+    write!(w, "{}:", nonce)?;
+    write!(w, "{}", miner_account)
+}
 
-        // check it's right length
-        if miner_account.len() != 116 {
-            return Err("Miner account is an invalid key".to_string());
+/// Writes the fields shared by posted and stored blocks, i.e. everything but
+/// the serial.
+fn write_block<W: std::fmt::Write>(
+    w: &mut W,
+    prev_hash: Option<Hash>,
+    timestamp: Option<u64>,
+    merkle_root: Option<Hash>,
+    nonce: u64,
+    miner_account: &str,
+    transactions: &[Transaction],
+) -> std::fmt::Result {
+    write_header(w, prev_hash, timestamp, merkle_root, nonce, miner_account)?;
+    write!(w, ":")?;
+    let num_transactions = transactions.len();
+    for (i, t) in transactions.iter().enumerate() {
+        t.help_fmt(w, ";")?;
+        if i != num_transactions - 1 {
+            write!(w, ":")?;
         }
+    }
+    Ok(())
+}
 
-        let transactions = split.get(3..).unwrap().to_vec();
-        let transactions = transactions
-            .iter()
-            .map(|x| x.replace(';', ":").parse::<Transaction>())
-            .collect::<Result<Vec<Transaction>, String>>()?;
-
-        Ok(NewBlock {
-            transactions,
-            nonce,
-            miner_account,
-        })
+impl Block {
+    /// Writes the block the way it was posted, without its serial.
+    fn write_body<W: std::fmt::Write>(&self, w: &mut W) -> std::fmt::Result {
+        write_block(
+            w,
+            self.prev_hash,
+            self.timestamp,
+            self.merkle_root,
+            self.nonce,
+            &self.miner_account,
+            &self.transactions,
+        )
     }
 }
 
 impl Display for Block {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}:", self.serial)?;
-        write!(f, "block:")?;
-        write!(f, "{}:", self.nonce)?;
-        write!(f, "{}:", self.miner_account)?;
-        let num_transactions = self.transactions.len();
-        for (i, t) in self.transactions.iter().enumerate() {
-            t.help_fmt(f, ";")?;
-            if i != num_transactions - 1 {
-                write!(f, ":")?;
-            }
-        }
-        Ok(())
+        self.write_body(f)
     }
 }
 
 impl Display for NewBlock {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "block:")?;
-        write!(f, "{}:", self.nonce)?;
-        write!(f, "{}:", self.miner_account)?;
-        let num_transactions = self.transactions.len();
-        for (i, t) in self.transactions.iter().enumerate() {
-            t.help_fmt(f, ";")?;
-            if i != num_transactions - 1 {
-                write!(f, ":")?;
-            }
-        }
-        Ok(())
+        write_block(
+            f,
+            self.prev_hash,
+            self.timestamp,
+            self.merkle_root,
+            self.nonce,
+            &self.miner_account,
+            &self.transactions,
+        )
     }
 }
 
+#[derive(Clone, Debug)]
 pub enum NewMessage {
     NewTransaction(NewTransaction),
     NewBlock(NewBlock),
 }
 
+#[derive(Clone, Debug)]
 pub enum Message {
     Block(Block),
     Transaction(Transaction),
 }
 
+impl Message {
+    pub fn serial(&self) -> u64 {
+        match self {
+            Message::Block(b) => b.serial,
+            Message::Transaction(t) => t.serial,
+        }
+    }
+}
+
 impl Display for Message {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -420,17 +336,7 @@ impl FromStr for Message {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let split = s.split(':').collect::<Vec<&str>>();
-        if split.len() < 2 {
-            return Err("Message has less than two parts".to_string());
-        }
-
-        let which = split.get(1).unwrap();
-        match *which {
-            "block" => Ok(Message::Block(s.parse::<Block>()?)),
-            "transaction" => Ok(Message::Transaction(s.parse::<Transaction>()?)),
-            _ => Err("Message is not block or transaction".to_string()),
-        }
+        MessageRef::parse(s).map(MessageRef::into_owned)
     }
 }
 
@@ -438,61 +344,253 @@ impl FromStr for NewMessage {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let split = s.split(':').collect::<Vec<&str>>();
-
-        if split.len() < 2 {
-            return Err("Message has less than two parts".to_string());
-        }
+        NewMessageRef::parse(s).map(NewMessageRef::into_owned)
+    }
+}
 
-        let which = split.get(0).unwrap();
+impl Message {
+    /// See [`MessageRef::parse_lenient`].
+    pub fn parse_lenient(s: &str) -> Result<Self, String> {
+        MessageRef::parse_lenient(s).map(MessageRef::into_owned)
+    }
+}
 
-        match *which {
-            "block" => Ok(NewMessage::NewBlock(s.parse::<NewBlock>()?)),
-            "transaction" => Ok(NewMessage::NewTransaction(s.parse::<NewTransaction>()?)),
-            _ => Err("Message is not block or transaction".to_string()),
-        }
+impl NewMessage {
+    /// See [`NewMessageRef::parse_lenient`].
+    pub fn parse_lenient(s: &str) -> Result<Self, String> {
+        NewMessageRef::parse_lenient(s).map(NewMessageRef::into_owned)
     }
 }
 
 #[cfg(test)]
 mod messages_tests {
+    use crate::{
+        amount::Amount,
+        test_util::{KEY, KEY2, SIG},
+    };
 
     #[test]
     fn test_move_from_str() {
-        let m = "a,1.0".parse::<super::Move>().unwrap();
-        assert_eq!(m.from, "a");
-        assert_eq!(m.amount, 1.0);
+        let m = format!("{},1.0", KEY2).parse::<super::Move>().unwrap();
+        assert_eq!(m.from, KEY2);
+        assert_eq!(m.amount, Amount::from_whole(1));
     }
 
     #[test]
     fn test_move_from_str_int() {
-        let m = "a,1".parse::<super::Move>().unwrap();
-        assert_eq!(m.from, "a");
-        assert_eq!(m.amount, 1.0);
+        let m = format!("{},1", KEY2).parse::<super::Move>().unwrap();
+        assert_eq!(m.from, KEY2);
+        assert_eq!(m.amount, Amount::from_whole(1));
+    }
+
+    #[test]
+    fn test_move_amount_is_strict_and_canonical() {
+        let m = format!("{},2.50", KEY2).parse::<super::Move>().unwrap();
+        assert_eq!(m.to_string(), format!("{},2.5", KEY2));
+        for bad in ["1e3", "NaN", "inf", "-1", "0.000000001"] {
+            assert!(format!("{},{}", KEY2, bad).parse::<super::Move>().is_err());
+        }
+        assert_eq!(
+            format!("{},0", KEY2).parse::<super::Move>().err().unwrap(),
+            "Amount must be positive"
+        );
+    }
+
+    #[test]
+    fn test_legacy_numbers_are_only_read_from_the_log() {
+        let block = format!(
+            "block:1337.6:{}:3;transaction;Zm9v;{};{};{},0.123456789;{}0.5",
+            KEY2,
+            SIG,
+            KEY,
+            KEY2,
+            super::refs::FEE_PREFIX
+        );
+        assert_eq!(
+            block
+                .replace("1337.6", "1337")
+                .parse::<super::NewMessage>()
+                .err()
+                .unwrap(),
+            "Amount has more than 8 decimal places"
+        );
+        assert!(block.parse::<super::NewMessage>().is_err());
+        let b = match super::NewMessage::parse_lenient(&block).unwrap() {
+            super::NewMessage::NewBlock(b) => b,
+            _ => panic!("not a block"),
+        };
+        assert_eq!(b.nonce, 1338);
+        assert_eq!(b.transactions[0].moves[0].amount.to_string(), "0.12345679");
+        assert_eq!(b.transactions[0].fee.to_string(), "0.5");
+
+        let stored = format!("4:block:1e30:{}:", KEY2);
+        assert!(stored.parse::<super::Message>().is_err());
+        let b = super::Message::parse_lenient(&stored).unwrap();
+        assert_eq!(b.to_string(), format!("4:block:{}:{}:", u64::MAX, KEY2));
+        assert!(super::Message::parse_lenient(&format!("4:block:NaN:{}:", KEY2)).is_err());
     }
 
     #[test]
     fn test_transaction_from_str() {
-        let t = "0:transaction:Zm9v:1:2,2.0"
+        let t = format!("0:transaction:Zm9v:{}:{}:{},2.0", SIG, KEY, KEY2)
             .parse::<super::Transaction>()
             .unwrap();
         assert_eq!(t.serial, 0);
-        assert_eq!(t.sig, "Zm9v");
+        assert_eq!(t.unique_string, "Zm9v");
+        assert_eq!(t.sig, SIG);
         assert_eq!(t.moves.len(), 1);
-        assert_eq!(t.sender, "1");
-        assert_eq!(t.moves.get(0).unwrap().from, "2");
-        assert_eq!(t.moves.get(0).unwrap().amount, 2.0);
+        assert_eq!(t.sender, KEY);
+        assert_eq!(t.moves[0].from, KEY2);
+        assert_eq!(t.moves[0].amount, Amount::from_whole(2));
     }
 
     #[test]
     fn test_new_transaction_from_str() {
-        let t = "transaction:Zm9v:1:2,2.0"
+        let t = format!("transaction:Zm9v:{}:{}:{},2.0", SIG, KEY, KEY2)
             .parse::<super::NewTransaction>()
             .unwrap();
-        assert_eq!(t.sig, "Zm9v");
+        assert_eq!(t.unique_string, "Zm9v");
+        assert_eq!(t.sig, SIG);
         assert_eq!(t.moves.len(), 1);
-        assert_eq!(t.sender, "1");
-        assert_eq!(t.moves.get(0).unwrap().from, "2");
-        assert_eq!(t.moves.get(0).unwrap().amount, 2.0);
+        assert_eq!(t.sender, KEY);
+        assert_eq!(t.moves[0].from, KEY2);
+        assert_eq!(t.moves[0].amount, Amount::from_whole(2));
+    }
+
+    #[test]
+    fn test_transaction_fee() {
+        let s = format!("transaction:Zm9v:{}:{}:{},2:fee,0.10", SIG, KEY, KEY2);
+        let t = s.parse::<super::NewTransaction>().unwrap();
+        assert_eq!(t.moves.len(), 1);
+        assert_eq!(t.fee, "0.1".parse::<Amount>().unwrap());
+        assert_eq!(t.to_string(), s.replace("0.10", "0.1"));
+
+        // transactions from before fees have none
+        let legacy = format!("transaction:Zm9v:{}:{}:{},2", SIG, KEY, KEY2);
+        let t = legacy.parse::<super::NewTransaction>().unwrap();
+        assert_eq!(t.fee, Amount::ZERO);
+        assert_eq!(t.to_string(), legacy);
+
+        let err = |s: String| s.parse::<super::NewTransaction>().err().unwrap();
+        assert_eq!(err(format!("{}:fee,0", legacy)), "Fee must be positive");
+        assert_eq!(err(format!("{}:fee,1e3", legacy)), "Fee is not a number");
+        assert_eq!(
+            err(format!("{}:fee,1:{},2", legacy, KEY2)),
+            "Fee must come after the moves"
+        );
+    }
+
+    #[test]
+    fn test_transaction_ref_borrows_input() {
+        let s = format!("3:transaction:Zm9v:{}:{}:{},2.5:{},1", SIG, KEY, KEY2, KEY);
+        let t = super::TransactionRef::parse(&s).unwrap();
+        assert_eq!(t.serial, 3);
+        assert_eq!(t.sender.as_ptr(), s[s.find(KEY).unwrap()..].as_ptr());
+        assert_eq!(t.moves.len(), 2);
+        assert_eq!(t.moves[0].amount, "2.5".parse::<Amount>().unwrap());
+        assert_eq!(t.into_owned().to_string(), s);
+    }
+
+    #[test]
+    fn test_block_from_str_embedded() {
+        let s = format!(
+            "7:block:42:{}:5;transaction;Zm9v;{};{};{},2:6;transaction;YmFy;{};{};{},1",
+            KEY, SIG, KEY, KEY2, SIG, KEY2, KEY
+        );
+        let b = s.parse::<super::Block>().unwrap();
+        assert_eq!(b.serial, 7);
+        assert_eq!(b.nonce, 42);
+        assert_eq!(b.transactions.len(), 2);
+        assert_eq!(b.transactions[1].serial, 6);
+        assert_eq!(b.transactions[1].sender, KEY2);
+        assert_eq!(b.to_string(), s);
+    }
+
+    #[test]
+    fn test_block_v2_prev_hash() {
+        let prev = super::NewBlock::genesis().hash();
+        let s = format!("block:v2:{}:7:{}:", prev, KEY2);
+        let b = s.parse::<super::NewBlock>().unwrap();
+        assert_eq!(b.prev_hash, Some(prev));
+        assert_eq!(b.nonce, 7);
+        assert_eq!(b.to_string(), s);
+
+        let stored = format!("4:{}", s).parse::<super::Block>().unwrap();
+        assert_eq!(stored.prev_hash, Some(prev));
+        assert_eq!(stored.to_string(), format!("4:{}", s));
+
+        // legacy blocks have no prev hash
+        let legacy = format!("block:7:{}:", KEY2).parse::<super::NewBlock>();
+        assert_eq!(legacy.unwrap().prev_hash, None);
+
+        assert_eq!(
+            format!("block:v2:abc:7:{}:", KEY2)
+                .parse::<super::NewBlock>()
+                .err()
+                .unwrap(),
+            "Prev hash is not a valid hash"
+        );
+    }
+
+    #[test]
+    fn test_block_v3_timestamp() {
+        let prev = super::NewBlock::genesis().hash();
+        let s = format!("block:v3:{}:1700000000:7:{}:", prev, KEY2);
+        let b = s.parse::<super::NewBlock>().unwrap();
+        assert_eq!(b.prev_hash, Some(prev));
+        assert_eq!(b.timestamp, Some(1700000000));
+        assert_eq!(b.nonce, 7);
+        assert_eq!(b.to_string(), s);
+
+        let stored = format!("4:{}", s).parse::<super::Block>().unwrap();
+        assert_eq!(stored.timestamp, Some(1700000000));
+        assert_eq!(stored.to_string(), format!("4:{}", s));
+
+        let v2 = format!("block:v2:{}:7:{}:", prev, KEY2);
+        assert_eq!(v2.parse::<super::NewBlock>().unwrap().timestamp, None);
+
+        assert_eq!(
+            format!("block:v3:{}:-1:7:{}:", prev, KEY2)
+                .parse::<super::NewBlock>()
+                .err()
+                .unwrap(),
+            "Timestamp is not a number"
+        );
+    }
+
+    #[test]
+    fn test_genesis_round_trip() {
+        let genesis = super::NewBlock::genesis().to_string();
+        let b = genesis.parse::<super::NewBlock>().unwrap();
+        assert!(b.transactions.is_empty());
+        assert_eq!(b.to_string(), genesis);
+
+        let stored = format!("0:{}", genesis);
+        match stored.parse::<super::Message>().unwrap() {
+            super::Message::Block(b) => assert_eq!(b.to_string(), stored),
+            super::Message::Transaction(_) => panic!("genesis parsed as a transaction"),
+        }
+    }
+
+    #[test]
+    fn test_message_errors() {
+        assert_eq!(
+            "0".parse::<super::Message>().err().unwrap(),
+            "Message has less than two parts"
+        );
+        assert_eq!(
+            format!("0:block:1:{}", KEY)
+                .parse::<super::Message>()
+                .err()
+                .unwrap(),
+            "Block has less than six parts"
+        );
+        assert_eq!(
+            "transaction:Zm9v:AAAA"
+                .parse::<super::NewMessage>()
+                .err()
+                .unwrap(),
+            "Transaction has less than four parts"
+        );
     }
 }
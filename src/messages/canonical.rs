@@ -0,0 +1,292 @@
+//! Canonical encodings and hashes of messages.
+//!
+//! A message's canonical encoding is its line format as posted, i.e. without
+//! the serial the server assigns, with every amount in canonical form. That
+//! way a client can compute the hash of a message before posting it, and the
+//! hash stays the same once the message is stored under a serial.
+//!
+//! A transaction's signature signs its canonical encoding with the signature
+//! field left empty.
+//!
+//! Blocks with a Merkle root are hashed by their header alone, which commits
+//! to their transactions through the root.
+
+use crate::{amount::Amount, hash::Hash, keys, merkle};
+
+use super::{
+    write_header, write_transaction, Block, Message, Move, NewBlock, NewMessage, NewTransaction,
+    Transaction,
+};
+
+fn signing_bytes(unique_string: &str, sender: &str, moves: &[Move], fee: Amount) -> Vec<u8> {
+    let mut buf = String::new();
+    write_transaction(&mut buf, ":", unique_string, "", sender, moves, fee).unwrap();
+    buf.into_bytes()
+}
+
+impl Transaction {
+    /// The transaction's leaf in the Merkle tree of a block including it,
+    /// which is the hash of the transaction as served.
+    pub fn merkle_leaf(&self) -> Hash {
+        Hash::of(self.to_string().as_bytes())
+    }
+
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        signing_bytes(&self.unique_string, &self.sender, &self.moves, self.fee)
+    }
+
+    pub fn verify_signature(&self) -> Result<(), String> {
+        keys::verify(&self.sender, &self.signing_bytes(), &self.sig)
+    }
+
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        let mut buf = String::new();
+        self.write_body(&mut buf, ":").unwrap();
+        buf.into_bytes()
+    }
+
+    pub fn hash(&self) -> Hash {
+        Hash::of(&self.canonical_bytes())
+    }
+}
+
+impl NewTransaction {
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        signing_bytes(&self.unique_string, &self.sender, &self.moves, self.fee)
+    }
+
+    pub fn verify_signature(&self) -> Result<(), String> {
+        keys::verify(&self.sender, &self.signing_bytes(), &self.sig)
+    }
+
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        self.to_string().into_bytes()
+    }
+
+    pub fn hash(&self) -> Hash {
+        Hash::of(&self.canonical_bytes())
+    }
+}
+
+/// Hashes a block by its header if it has a Merkle root, otherwise by the
+/// whole block.
+fn block_hash(
+    header: String,
+    merkle_root: Option<Hash>,
+    canonical_bytes: impl FnOnce() -> Vec<u8>,
+) -> Hash {
+    match merkle_root {
+        Some(_) => Hash::of(header.as_bytes()),
+        None => Hash::of(&canonical_bytes()),
+    }
+}
+
+fn merkle_root(transactions: &[Transaction]) -> Hash {
+    let leaves = transactions
+        .iter()
+        .map(Transaction::merkle_leaf)
+        .collect::<Vec<_>>();
+    merkle::root(&leaves)
+}
+
+impl Block {
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        let mut buf = String::new();
+        self.write_body(&mut buf).unwrap();
+        buf.into_bytes()
+    }
+
+    /// The block without its serial and transactions.
+    pub fn header(&self) -> String {
+        let mut buf = String::new();
+        write_header(
+            &mut buf,
+            self.prev_hash,
+            self.timestamp,
+            self.merkle_root,
+            self.nonce,
+            &self.miner_account,
+        )
+        .unwrap();
+        buf
+    }
+
+    pub fn compute_merkle_root(&self) -> Hash {
+        merkle_root(&self.transactions)
+    }
+
+    pub fn hash(&self) -> Hash {
+        block_hash(self.header(), self.merkle_root, || self.canonical_bytes())
+    }
+}
+
+impl NewBlock {
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        self.to_string().into_bytes()
+    }
+
+    /// The block without its transactions.
+    pub fn header(&self) -> String {
+        let mut buf = String::new();
+        write_header(
+            &mut buf,
+            self.prev_hash,
+            self.timestamp,
+            self.merkle_root,
+            self.nonce,
+            &self.miner_account,
+        )
+        .unwrap();
+        buf
+    }
+
+    pub fn compute_merkle_root(&self) -> Hash {
+        merkle_root(&self.transactions)
+    }
+
+    pub fn hash(&self) -> Hash {
+        block_hash(self.header(), self.merkle_root, || self.canonical_bytes())
+    }
+}
+
+impl Message {
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        match self {
+            Message::Block(b) => b.canonical_bytes(),
+            Message::Transaction(t) => t.canonical_bytes(),
+        }
+    }
+
+    pub fn hash(&self) -> Hash {
+        match self {
+            Message::Block(b) => b.hash(),
+            Message::Transaction(t) => t.hash(),
+        }
+    }
+}
+
+impl NewMessage {
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        match self {
+            NewMessage::NewBlock(b) => b.canonical_bytes(),
+            NewMessage::NewTransaction(t) => t.canonical_bytes(),
+        }
+    }
+
+    pub fn hash(&self) -> Hash {
+        match self {
+            NewMessage::NewBlock(b) => b.hash(),
+            NewMessage::NewTransaction(t) => t.hash(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod canonical_tests {
+    use rsa::{rand_core::OsRng, RsaPrivateKey};
+
+    use crate::{
+        hash::Hash,
+        keys,
+        messages::{Message, NewBlock, NewMessage, NewTransaction, Transaction},
+        test_util::{KEY, KEY2, SIG},
+    };
+
+    #[test]
+    fn test_genesis_hash() {
+        let genesis = NewBlock::genesis();
+        assert_eq!(
+            genesis.canonical_bytes(),
+            format!("block:1337:{}:", KEY).as_bytes()
+        );
+        assert_eq!(
+            genesis.hash().to_string(),
+            "fa429acf1df8e59906dec263db130c3f3258ed9b5cebdc47881d2e9c88f7a7e0"
+        );
+    }
+
+    #[test]
+    fn test_transaction_hash_ignores_serial() {
+        let expected = "6f263b3ce878f2d0ce12a1c3b2c41072af43ce0cc0bc4b10663dbd974fc7d617";
+
+        // non-canonical amounts hash the same as their canonical form
+        let posted = format!("transaction:Zm9v:{}:{}:{},2.50", SIG, KEY, KEY2)
+            .parse::<NewMessage>()
+            .unwrap();
+        assert_eq!(posted.hash().to_string(), expected);
+
+        let stored = format!("9:transaction:Zm9v:{}:{}:{},2.5", SIG, KEY, KEY2)
+            .parse::<Message>()
+            .unwrap();
+        assert_eq!(stored.hash().to_string(), expected);
+        assert_eq!(stored.canonical_bytes(), posted.canonical_bytes());
+    }
+
+    #[test]
+    fn test_signature_covers_everything_but_itself() {
+        let private = RsaPrivateKey::new(&mut OsRng, 512).unwrap();
+        let sender = keys::encode_public_key(&private.to_public_key());
+        let unsigned = format!("transaction:Zm9v:{}:{}:{},2.5", SIG, sender, KEY2)
+            .parse::<NewTransaction>()
+            .unwrap();
+        assert_eq!(
+            unsigned.signing_bytes(),
+            format!("transaction:Zm9v::{}:{},2.5", sender, KEY2).as_bytes()
+        );
+        assert!(unsigned.verify_signature().is_err());
+
+        let sig = keys::sign(&private, &unsigned.signing_bytes());
+        let signed = unsigned.to_string().replace(SIG, &sig);
+        let stored = format!("3:{}", signed).parse::<Transaction>().unwrap();
+        assert!(stored.verify_signature().is_ok());
+
+        let tampered = format!("3:{}", signed.replace(",2.5", ",25"))
+            .parse::<Transaction>()
+            .unwrap();
+        assert!(tampered.verify_signature().is_err());
+    }
+
+    #[test]
+    fn test_v4_block_hash_covers_header() {
+        let tx = format!("5;transaction;Zm9v;{};{};{},2.5", SIG, KEY, KEY2);
+        let v3 = format!("block:v3:{}:100:42:{}:{}", Hash::ZERO, KEY, tx)
+            .parse::<NewBlock>()
+            .unwrap();
+        let mut block = v3.clone();
+        block.merkle_root = Some(block.compute_merkle_root());
+        assert_eq!(
+            block.compute_merkle_root(),
+            Hash::of(format!("5:transaction:Zm9v:{}:{}:{},2.5", SIG, KEY, KEY2).as_bytes())
+        );
+
+        let header = format!(
+            "block:v4:{}:100:{}:42:{}",
+            Hash::ZERO,
+            block.compute_merkle_root(),
+            KEY
+        );
+        assert_eq!(block.header(), header);
+        assert_eq!(block.to_string(), format!("{}:{}", header, tx));
+        assert_eq!(block.hash(), Hash::of(header.as_bytes()));
+        assert_ne!(v3.hash(), Hash::of(v3.header().as_bytes()));
+
+        let stored = format!("7:{}", block).parse::<Message>().unwrap();
+        assert_eq!(stored.hash(), block.hash());
+        assert_eq!(stored.to_string(), format!("7:{}", block));
+    }
+
+    #[test]
+    fn test_block_hash() {
+        let expected = "dad43ae9425f898261f5da7794df8d13a8e9f454fcfee45b9e32c4cdfe5ba5e5";
+        let body = format!(
+            "block:42:{}:5;transaction;Zm9v;{};{};{},2.5",
+            KEY, SIG, KEY, KEY2
+        );
+
+        let posted = body.parse::<NewMessage>().unwrap();
+        assert_eq!(posted.hash().to_string(), expected);
+
+        let stored = format!("10:{}", body).parse::<Message>().unwrap();
+        assert_eq!(stored.hash().to_string(), expected);
+    }
+}
@@ -0,0 +1,692 @@
+use crate::{amount::Amount, hash::Hash};
+
+use super::{Block, Message, Move, NewBlock, NewMessage, NewTransaction, Transaction};
+
+/// How numbers are read. Posted messages have to write them in canonical
+/// form, but the log holds lines from clients that wrote amounts and nonces
+/// as floats, which are rounded instead.
+#[derive(Clone, Copy)]
+enum Strictness {
+    Strict,
+    Lenient,
+}
+
+/// Splits a message on `sep` one field at a time, without collecting or
+/// copying anything.
+struct Fields<'a> {
+    inner: std::iter::Peekable<std::str::Split<'a, char>>,
+}
+
+impl<'a> Fields<'a> {
+    fn new(s: &'a str, sep: char) -> Self {
+        Fields {
+            inner: s.split(sep).peekable(),
+        }
+    }
+
+    /// Returns the next field, or `err` if the message ran out of fields.
+    fn next_or(&mut self, err: &str) -> Result<&'a str, String> {
+        self.inner.next().ok_or_else(|| err.to_string())
+    }
+
+    /// Whether every field has been read.
+    fn is_done(&mut self) -> bool {
+        self.inner.peek().is_none()
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+}
+
+/// Checks that `s` is valid base64, decoding into a stack buffer when it fits.
+fn is_base64(s: &str) -> bool {
+    let mut buf = [0u8; 192];
+    if s.len().div_ceil(4) * 3 <= buf.len() {
+        base64::decode_config_slice(s, base64::STANDARD, &mut buf).is_ok()
+    } else {
+        base64::decode(s).is_ok()
+    }
+}
+
+fn parse_serial(s: &str) -> Result<u64, String> {
+    s.parse::<u64>()
+        .map_err(|_| "Serial is not a number".to_string())
+}
+
+fn parse_unique_string(s: &str) -> Result<&str, String> {
+    // check it's actually base64
+    if !is_base64(s) {
+        return Err("Unique string is not base64".to_string());
+    }
+
+    // check it's at least 1 char
+    if s.is_empty() {
+        return Err("Unique string is too short".to_string());
+    }
+    Ok(s)
+}
+
+fn parse_sig(s: &str) -> Result<&str, String> {
+    // check it's actually base64
+    if !is_base64(s) {
+        return Err("Signature is not base64".to_string());
+    }
+
+    // check it's right length
+    if s.len() != 88 {
+        return Err("Signature has an invalid length".to_string());
+    }
+    Ok(s)
+}
+
+fn parse_sender(s: &str) -> Result<&str, String> {
+    // check it's actually base64
+    if !is_base64(s) {
+        return Err(format!("Sender public key ({}) is not base64", s));
+    }
+
+    // check it's right length
+    if s.len() != 116 {
+        return Err(format!("Sender public key ({}) is an invalid key", s));
+    }
+    Ok(s)
+}
+
+fn parse_miner_account(s: &str) -> Result<&str, String> {
+    // check it's actually base64
+    if !is_base64(s) {
+        return Err("Miner account is not base64".to_string());
+    }
+
+    // check it's right length
+    if s.len() != 116 {
+        return Err("Miner account is an invalid key".to_string());
+    }
+    Ok(s)
+}
+
+fn parse_prev_hash(s: &str) -> Result<Hash, String> {
+    s.parse::<Hash>()
+        .map_err(|_| "Prev hash is not a valid hash".to_string())
+}
+
+fn parse_merkle_root(s: &str) -> Result<Hash, String> {
+    s.parse::<Hash>()
+        .map_err(|_| "Merkle root is not a valid hash".to_string())
+}
+
+fn parse_timestamp(s: &str) -> Result<u64, String> {
+    s.parse::<u64>()
+        .map_err(|_| "Timestamp is not a number".to_string())
+}
+
+fn parse_nonce(s: &str, strictness: Strictness) -> Result<u64, String> {
+    let err = || "Nonce is not a number".to_string();
+    match (s.parse::<u64>(), strictness) {
+        (Ok(nonce), _) => Ok(nonce),
+        (Err(_), Strictness::Strict) => Err(err()),
+        // the cast saturates, so the rounded nonce stays in range
+        (Err(_), Strictness::Lenient) => match s.parse::<f64>() {
+            Ok(x) if !x.is_nan() => Ok(x.round() as u64),
+            _ => Err(err()),
+        },
+    }
+}
+
+fn parse_amount(s: &str, strictness: Strictness) -> Result<Amount, String> {
+    match strictness {
+        Strictness::Strict => s.parse::<Amount>(),
+        Strictness::Lenient => Amount::parse_lenient(s),
+    }
+}
+
+/// A borrowed view of a [`Move`].
+pub struct MoveRef<'a> {
+    pub from: &'a str,
+    pub amount: Amount,
+}
+
+impl<'a> MoveRef<'a> {
+    pub fn parse(s: &'a str) -> Result<Self, String> {
+        Self::parse_as(s, Strictness::Strict)
+    }
+
+    fn parse_as(s: &'a str, strictness: Strictness) -> Result<Self, String> {
+        let (from, amount) = match s.split_once(',') {
+            Some((from, amount)) if !amount.contains(',') => (from, amount),
+            _ => return Err("Move has more than two parts".to_string()),
+        };
+
+        // check it's actually base64
+        if !is_base64(from) {
+            return Err(format!("Recipient public key ({}) is not base64", from));
+        }
+
+        // check it's right length
+        if from.len() != 116 {
+            return Err(format!("Recipient public key ({}) is an invalid key", from));
+        }
+
+        let amount = parse_amount(amount, strictness)?;
+
+        // check that the amount is positive
+        if amount.is_zero() {
+            return Err("Amount must be positive".to_string());
+        }
+
+        Ok(MoveRef { from, amount })
+    }
+
+    pub fn into_owned(self) -> Move {
+        Move {
+            from: self.from.to_string(),
+            amount: self.amount,
+        }
+    }
+}
+
+/// Prefixes the optional fee field, which follows the moves. It can't be
+/// mistaken for a move since `fee` isn't a valid key.
+pub(super) const FEE_PREFIX: &str = "fee,";
+
+/// Parses the moves of a transaction and the fee after them, if any.
+fn parse_moves<'a>(
+    fields: Fields<'a>,
+    strictness: Strictness,
+) -> Result<(Vec<MoveRef<'a>>, Amount), String> {
+    let mut moves = vec![];
+    let mut fields = fields.peekable();
+    while let Some(field) = fields.next() {
+        if let Some(fee) = field.strip_prefix(FEE_PREFIX) {
+            if fields.peek().is_some() {
+                return Err("Fee must come after the moves".to_string());
+            }
+            let fee = parse_amount(fee, strictness).map_err(|e| e.replacen("Amount", "Fee", 1))?;
+
+            // a transaction without a fee leaves the field out
+            if fee.is_zero() {
+                return Err("Fee must be positive".to_string());
+            }
+            return Ok((moves, fee));
+        }
+        moves.push(MoveRef::parse_as(field, strictness)?);
+    }
+    Ok((moves, Amount::ZERO))
+}
+
+const TRANSACTION_SHORT: &str = "Transaction has less than five parts";
+
+/// A borrowed view of a [`Transaction`].
+pub struct TransactionRef<'a> {
+    pub serial: u64,
+    pub unique_string: &'a str,
+    pub sig: &'a str,
+    pub sender: &'a str,
+    pub moves: Vec<MoveRef<'a>>,
+    pub fee: Amount,
+}
+
+impl<'a> TransactionRef<'a> {
+    pub fn parse(s: &'a str) -> Result<Self, String> {
+        Self::parse_with(s, ':')
+    }
+
+    /// Parses a transaction whose fields are separated by `sep`. Blocks embed
+    /// their transactions with `;` instead of `:`.
+    pub fn parse_with(s: &'a str, sep: char) -> Result<Self, String> {
+        Self::parse_as(s, sep, Strictness::Strict)
+    }
+
+    fn parse_as(s: &'a str, sep: char, strictness: Strictness) -> Result<Self, String> {
+        let mut fields = Fields::new(s, sep);
+        let serial = fields.next_or(TRANSACTION_SHORT)?;
+        let kind = fields.next_or(TRANSACTION_SHORT)?;
+        Self::parse_fields(serial, kind, fields, strictness)
+    }
+
+    /// Parses the fields after the serial and type, which has to be
+    /// `transaction`.
+    fn parse_fields(
+        serial: &str,
+        kind: &str,
+        mut fields: Fields<'a>,
+        strictness: Strictness,
+    ) -> Result<Self, String> {
+        const SHORT: &str = TRANSACTION_SHORT;
+        let unique_string = fields.next_or(SHORT)?;
+        let sig = fields.next_or(SHORT)?;
+        let sender = fields.next_or(SHORT)?;
+
+        let serial = parse_serial(serial)?;
+
+        // check second is transaction
+        if kind != "transaction" {
+            return Err("Second part is not transaction".to_string());
+        }
+
+        let unique_string = parse_unique_string(unique_string)?;
+        let sig = parse_sig(sig)?;
+        let sender = parse_sender(sender)?;
+        let (moves, fee) = parse_moves(fields, strictness)?;
+
+        Ok(TransactionRef {
+            serial,
+            unique_string,
+            sig,
+            sender,
+            moves,
+            fee,
+        })
+    }
+
+    pub fn into_owned(self) -> Transaction {
+        Transaction {
+            serial: self.serial,
+            unique_string: self.unique_string.to_string(),
+            sig: self.sig.to_string(),
+            sender: self.sender.to_string(),
+            moves: self.moves.into_iter().map(MoveRef::into_owned).collect(),
+            fee: self.fee,
+        }
+    }
+}
+
+const NEW_TRANSACTION_SHORT: &str = "Transaction has less than four parts";
+
+/// A borrowed view of a [`NewTransaction`].
+pub struct NewTransactionRef<'a> {
+    pub unique_string: &'a str,
+    pub sig: &'a str,
+    pub sender: &'a str,
+    pub moves: Vec<MoveRef<'a>>,
+    pub fee: Amount,
+}
+
+impl<'a> NewTransactionRef<'a> {
+    pub fn parse(s: &'a str) -> Result<Self, String> {
+        let mut fields = Fields::new(s, ':');
+        let kind = fields.next_or(NEW_TRANSACTION_SHORT)?;
+        Self::parse_fields(kind, fields, Strictness::Strict)
+    }
+
+    /// Parses the fields after the type, which has to be `transaction`.
+    fn parse_fields(
+        kind: &str,
+        mut fields: Fields<'a>,
+        strictness: Strictness,
+    ) -> Result<Self, String> {
+        const SHORT: &str = NEW_TRANSACTION_SHORT;
+        let unique_string = fields.next_or(SHORT)?;
+        let sig = fields.next_or(SHORT)?;
+        let sender = fields.next_or(SHORT)?;
+
+        // check first is transaction
+        if kind != "transaction" {
+            return Err("First part is not transaction".to_string());
+        }
+
+        let unique_string = parse_unique_string(unique_string)?;
+        let sig = parse_sig(sig)?;
+        let sender = parse_sender(sender)?;
+        let (moves, fee) = parse_moves(fields, strictness)?;
+
+        Ok(NewTransactionRef {
+            unique_string,
+            sig,
+            sender,
+            moves,
+            fee,
+        })
+    }
+
+    pub fn into_owned(self) -> NewTransaction {
+        NewTransaction {
+            unique_string: self.unique_string.to_string(),
+            sig: self.sig.to_string(),
+            sender: self.sender.to_string(),
+            moves: self.moves.into_iter().map(MoveRef::into_owned).collect(),
+            fee: self.fee,
+        }
+    }
+}
+
+/// Parses the transactions embedded in a block. `short` is reported when the
+/// block doesn't even have the trailing transaction field.
+fn parse_embedded<'a>(
+    mut fields: Fields<'a>,
+    short: &str,
+    strictness: Strictness,
+) -> Result<Vec<TransactionRef<'a>>, String> {
+    let first = fields.next_or(short)?;
+    let mut rest = fields.peekable();
+
+    // a block without transactions is written with a single empty field
+    if first.is_empty() && rest.peek().is_none() {
+        return Ok(vec![]);
+    }
+
+    std::iter::once(first)
+        .chain(rest)
+        .map(|x| TransactionRef::parse_as(x, ';', strictness))
+        .collect()
+}
+
+/// Marks a block written in the second version of the format, which adds the
+/// hash of the previous block before the nonce.
+pub(super) const BLOCK_V2: &str = "v2";
+
+/// Marks a block written in the third version of the format, which adds the
+/// time it was mined after the hash of the previous block.
+pub(super) const BLOCK_V3: &str = "v3";
+
+/// Marks a block written in the fourth version of the format, which adds the
+/// Merkle root of its transactions after the timestamp. Its hash covers only
+/// the header, so a block can be checked without its transactions.
+pub(super) const BLOCK_V4: &str = "v4";
+
+/// The unparsed header fields of a block, in any version of the format.
+struct RawHeader<'a> {
+    prev_hash: Option<&'a str>,
+    timestamp: Option<&'a str>,
+    merkle_root: Option<&'a str>,
+    nonce: &'a str,
+    miner_account: &'a str,
+}
+
+impl<'a> RawHeader<'a> {
+    fn next(fields: &mut Fields<'a>, short: &str) -> Result<Self, String> {
+        let first = fields.next_or(short)?;
+        let version = match first {
+            BLOCK_V2 => 2,
+            BLOCK_V3 => 3,
+            BLOCK_V4 => 4,
+            _ => 1,
+        };
+
+        let prev_hash = if version >= 2 {
+            Some(fields.next_or(short)?)
+        } else {
+            None
+        };
+        let timestamp = if version >= 3 {
+            Some(fields.next_or(short)?)
+        } else {
+            None
+        };
+        let merkle_root = if version >= 4 {
+            Some(fields.next_or(short)?)
+        } else {
+            None
+        };
+        let nonce = if version >= 2 {
+            fields.next_or(short)?
+        } else {
+            first
+        };
+        let miner_account = fields.next_or(short)?;
+
+        Ok(RawHeader {
+            prev_hash,
+            timestamp,
+            merkle_root,
+            nonce,
+            miner_account,
+        })
+    }
+}
+
+const BLOCK_SHORT: &str = "Block has less than six parts";
+
+/// A borrowed view of a [`Block`].
+pub struct BlockRef<'a> {
+    pub serial: u64,
+    pub prev_hash: Option<Hash>,
+    pub timestamp: Option<u64>,
+    pub merkle_root: Option<Hash>,
+    pub transactions: Vec<TransactionRef<'a>>,
+    pub nonce: u64,
+    pub miner_account: &'a str,
+}
+
+impl<'a> BlockRef<'a> {
+    pub fn parse(s: &'a str) -> Result<Self, String> {
+        let mut fields = Fields::new(s, ':');
+        let serial = fields.next_or(BLOCK_SHORT)?;
+        let kind = fields.next_or(BLOCK_SHORT)?;
+        Self::parse_fields(serial, kind, fields, Strictness::Strict)
+    }
+
+    /// Parses the fields after the serial and type, which has to be `block`.
+    fn parse_fields(
+        serial: &str,
+        kind: &str,
+        mut fields: Fields<'a>,
+        strictness: Strictness,
+    ) -> Result<Self, String> {
+        const SHORT: &str = BLOCK_SHORT;
+        let header = RawHeader::next(&mut fields, SHORT)?;
+        let transactions = parse_embedded(fields, SHORT, strictness)?;
+
+        let serial = parse_serial(serial)?;
+
+        // check second is block
+        if kind != "block" {
+            return Err("Second part is not block".to_string());
+        }
+
+        let prev_hash = header.prev_hash.map(parse_prev_hash).transpose()?;
+        let timestamp = header.timestamp.map(parse_timestamp).transpose()?;
+        let merkle_root = header.merkle_root.map(parse_merkle_root).transpose()?;
+        let nonce = parse_nonce(header.nonce, strictness)?;
+        let miner_account = parse_miner_account(header.miner_account)?;
+
+        Ok(BlockRef {
+            serial,
+            prev_hash,
+            timestamp,
+            merkle_root,
+            transactions,
+            nonce,
+            miner_account,
+        })
+    }
+
+    pub fn into_owned(self) -> Block {
+        Block {
+            serial: self.serial,
+            transactions: self
+                .transactions
+                .into_iter()
+                .map(TransactionRef::into_owned)
+                .collect(),
+            prev_hash: self.prev_hash,
+            timestamp: self.timestamp,
+            merkle_root: self.merkle_root,
+            nonce: self.nonce,
+            miner_account: self.miner_account.to_string(),
+        }
+    }
+}
+
+const NEW_BLOCK_SHORT: &str = "Block has less than five parts";
+
+/// A borrowed view of a [`NewBlock`].
+pub struct NewBlockRef<'a> {
+    pub prev_hash: Option<Hash>,
+    pub timestamp: Option<u64>,
+    pub merkle_root: Option<Hash>,
+    pub transactions: Vec<TransactionRef<'a>>,
+    pub nonce: u64,
+    pub miner_account: &'a str,
+}
+
+impl<'a> NewBlockRef<'a> {
+    pub fn parse(s: &'a str) -> Result<Self, String> {
+        Self::parse_as(s, Strictness::Strict)
+    }
+
+    /// Parses a block from the log, rounding amounts and nonces that
+    /// legacy clients wrote as floats.
+    pub fn parse_lenient(s: &'a str) -> Result<Self, String> {
+        Self::parse_as(s, Strictness::Lenient)
+    }
+
+    fn parse_as(s: &'a str, strictness: Strictness) -> Result<Self, String> {
+        let mut fields = Fields::new(s, ':');
+        let kind = fields.next_or(NEW_BLOCK_SHORT)?;
+        Self::parse_fields(kind, fields, strictness)
+    }
+
+    /// Parses the fields after the type, which has to be `block`.
+    fn parse_fields(
+        kind: &str,
+        mut fields: Fields<'a>,
+        strictness: Strictness,
+    ) -> Result<Self, String> {
+        const SHORT: &str = NEW_BLOCK_SHORT;
+        let header = RawHeader::next(&mut fields, SHORT)?;
+        let transactions = parse_embedded(fields, SHORT, strictness)?;
+
+        // check second is block
+        if kind != "block" {
+            return Err("Second part is not block".to_string());
+        }
+
+        let prev_hash = header.prev_hash.map(parse_prev_hash).transpose()?;
+        let timestamp = header.timestamp.map(parse_timestamp).transpose()?;
+        let merkle_root = header.merkle_root.map(parse_merkle_root).transpose()?;
+        let nonce = parse_nonce(header.nonce, strictness)?;
+        let miner_account = parse_miner_account(header.miner_account)?;
+
+        Ok(NewBlockRef {
+            prev_hash,
+            timestamp,
+            merkle_root,
+            transactions,
+            nonce,
+            miner_account,
+        })
+    }
+
+    /// The block without its transactions, see [`NewBlock::header`].
+    pub fn header(&self) -> String {
+        let mut buf = String::new();
+        super::write_header(
+            &mut buf,
+            self.prev_hash,
+            self.timestamp,
+            self.merkle_root,
+            self.nonce,
+            self.miner_account,
+        )
+        .unwrap();
+        buf
+    }
+
+    pub fn into_owned(self) -> NewBlock {
+        NewBlock {
+            transactions: self
+                .transactions
+                .into_iter()
+                .map(TransactionRef::into_owned)
+                .collect(),
+            prev_hash: self.prev_hash,
+            timestamp: self.timestamp,
+            merkle_root: self.merkle_root,
+            nonce: self.nonce,
+            miner_account: self.miner_account.to_string(),
+        }
+    }
+}
+
+/// A borrowed view of a [`Message`].
+pub enum MessageRef<'a> {
+    Block(BlockRef<'a>),
+    Transaction(TransactionRef<'a>),
+}
+
+impl<'a> MessageRef<'a> {
+    pub fn parse(s: &'a str) -> Result<Self, String> {
+        Self::parse_as(s, Strictness::Strict)
+    }
+
+    /// Parses a message from the log, rounding amounts and nonces that
+    /// legacy clients wrote as floats.
+    pub fn parse_lenient(s: &'a str) -> Result<Self, String> {
+        Self::parse_as(s, Strictness::Lenient)
+    }
+
+    fn parse_as(s: &'a str, strictness: Strictness) -> Result<Self, String> {
+        const SHORT: &str = "Message has less than two parts";
+        let mut fields = Fields::new(s, ':');
+        let serial = fields.next_or(SHORT)?;
+        let which = fields.next_or(SHORT)?;
+
+        // carry on from the fields already read rather than starting over
+        match which {
+            "block" => Ok(MessageRef::Block(BlockRef::parse_fields(
+                serial, which, fields, strictness,
+            )?)),
+            "transaction" => Ok(MessageRef::Transaction(TransactionRef::parse_fields(
+                serial, which, fields, strictness,
+            )?)),
+            _ => Err("Message is not block or transaction".to_string()),
+        }
+    }
+
+    pub fn into_owned(self) -> Message {
+        match self {
+            MessageRef::Block(b) => Message::Block(b.into_owned()),
+            MessageRef::Transaction(t) => Message::Transaction(t.into_owned()),
+        }
+    }
+}
+
+/// A borrowed view of a [`NewMessage`].
+pub enum NewMessageRef<'a> {
+    NewTransaction(NewTransactionRef<'a>),
+    NewBlock(NewBlockRef<'a>),
+}
+
+impl<'a> NewMessageRef<'a> {
+    pub fn parse(s: &'a str) -> Result<Self, String> {
+        Self::parse_as(s, Strictness::Strict)
+    }
+
+    /// Parses a message from the log, rounding amounts and nonces that
+    /// legacy clients wrote as floats.
+    pub fn parse_lenient(s: &'a str) -> Result<Self, String> {
+        Self::parse_as(s, Strictness::Lenient)
+    }
+
+    fn parse_as(s: &'a str, strictness: Strictness) -> Result<Self, String> {
+        let mut fields = Fields::new(s, ':');
+        let which = fields.next().unwrap_or_default();
+        if fields.is_done() {
+            return Err("Message has less than two parts".to_string());
+        }
+
+        // carry on from the fields already read rather than starting over
+        match which {
+            "block" => Ok(NewMessageRef::NewBlock(NewBlockRef::parse_fields(
+                which, fields, strictness,
+            )?)),
+            "transaction" => Ok(NewMessageRef::NewTransaction(
+                NewTransactionRef::parse_fields(which, fields, strictness)?,
+            )),
+            _ => Err("Message is not block or transaction".to_string()),
+        }
+    }
+
+    pub fn into_owned(self) -> NewMessage {
+        match self {
+            NewMessageRef::NewTransaction(t) => NewMessage::NewTransaction(t.into_owned()),
+            NewMessageRef::NewBlock(b) => NewMessage::NewBlock(b.into_owned()),
+        }
+    }
+}
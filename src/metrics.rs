@@ -0,0 +1,375 @@
+//! Counting what the server does, for `GET /metrics`.
+//!
+//! Metrics are kept in memory from when the server starts and written out in
+//! the Prometheus text format. Labels only ever take one of a fixed set of
+//! values, so clients can't make the server keep track of ever more series.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use hyper::Method;
+
+use crate::storage::Storage;
+
+/// The upper bounds of the latency buckets, in seconds.
+const BUCKETS: [f64; 11] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+#[derive(Clone, Debug, Default)]
+struct Histogram {
+    // how many fell in each bucket, not counting the ones before it
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|&bound| secs <= bound) {
+            self.buckets[i] += 1;
+        }
+        self.sum += secs;
+        self.count += 1;
+    }
+
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        let mut below = 0;
+        for (bound, n) in BUCKETS.iter().zip(self.buckets) {
+            below += n;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}le=\"{}\"}} {}",
+                name, labels, bound, below
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}le=\"+Inf\"}} {}",
+            name, labels, self.count
+        );
+        let labels = labels.trim_end_matches(',');
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+    }
+}
+
+/// The methods counted by name, where any other is counted as `other`.
+const METHODS: [&str; 9] = [
+    "GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH",
+];
+
+/// A request, as counted: its method, route and status.
+type RequestKey = (&'static str, &'static str, u16);
+
+/// Why a message was turned away, as counted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    /// The sender or the address it came from is banned.
+    Banned,
+    /// A relay without the relay token.
+    Unauthorized,
+    /// The body isn't a message.
+    Malformed,
+    ReadOnly,
+    Duplicate,
+    InvalidTransaction,
+    InvalidBlock,
+    /// The message couldn't be stored.
+    Storage,
+}
+
+impl Rejection {
+    fn label(self) -> &'static str {
+        match self {
+            Rejection::Banned => "banned",
+            Rejection::Unauthorized => "unauthorized",
+            Rejection::Malformed => "malformed",
+            Rejection::ReadOnly => "read_only",
+            Rejection::Duplicate => "duplicate",
+            Rejection::InvalidTransaction => "invalid_transaction",
+            Rejection::InvalidBlock => "invalid_block",
+            Rejection::Storage => "storage",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<RequestKey, Histogram>>,
+    accepted: Mutex<BTreeMap<&'static str, u64>>,
+    rejected: Mutex<BTreeMap<&'static str, u64>>,
+    storage: Mutex<BTreeMap<&'static str, Histogram>>,
+}
+
+/// What the chain looks like when metrics are written out.
+pub struct ChainStats {
+    pub log_len: u64,
+    pub height: Option<u64>,
+    pub mempool: usize,
+}
+
+impl Metrics {
+    pub fn request(&self, method: &Method, route: &'static str, status: u16, elapsed: Duration) {
+        let mut requests = self.requests.lock().unwrap();
+        let key = (method_label(method), route, status);
+        requests.entry(key).or_default().observe(elapsed);
+    }
+
+    /// Counts an accepted message of `kind`, e.g. `transaction`.
+    pub fn accepted(&self, kind: &'static str) {
+        *self.accepted.lock().unwrap().entry(kind).or_default() += 1;
+    }
+
+    /// Counts a message turned away for `reason`.
+    pub fn rejected(&self, reason: Rejection) {
+        *self
+            .rejected
+            .lock()
+            .unwrap()
+            .entry(reason.label())
+            .or_default() += 1;
+    }
+
+    fn storage(&self, operation: &'static str, elapsed: Duration) {
+        let mut storage = self.storage.lock().unwrap();
+        storage.entry(operation).or_default().observe(elapsed);
+    }
+
+    /// Writes every metric out in the Prometheus text format.
+    pub fn render(&self, chain: &ChainStats) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "http_requests_total",
+            "counter",
+            "Requests served.",
+        );
+        let requests = self.requests.lock().unwrap().clone();
+        for ((method, route, status), histogram) in &requests {
+            let _ = writeln!(
+                out,
+                "http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}",
+                method, route, status, histogram.count
+            );
+        }
+        header(
+            &mut out,
+            "http_request_duration_seconds",
+            "histogram",
+            "How long requests took to answer.",
+        );
+        for ((method, route, status), histogram) in &requests {
+            let labels = format!(
+                "method=\"{}\",route=\"{}\",status=\"{}\",",
+                method, route, status
+            );
+            histogram.write(&mut out, "http_request_duration_seconds", &labels);
+        }
+
+        header(
+            &mut out,
+            "messages_accepted_total",
+            "counter",
+            "Messages appended to the log.",
+        );
+        for (kind, n) in self.accepted.lock().unwrap().iter() {
+            let _ = writeln!(out, "messages_accepted_total{{kind=\"{}\"}} {}", kind, n);
+        }
+        header(
+            &mut out,
+            "messages_rejected_total",
+            "counter",
+            "Messages turned away.",
+        );
+        for (reason, n) in self.rejected.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "messages_rejected_total{{reason=\"{}\"}} {}",
+                reason, n
+            );
+        }
+
+        header(&mut out, "log_length", "gauge", "Messages in the log.");
+        let _ = writeln!(out, "log_length {}", chain.log_len);
+        if let Some(height) = chain.height {
+            header(
+                &mut out,
+                "chain_height",
+                "gauge",
+                "Height of the canonical tip.",
+            );
+            let _ = writeln!(out, "chain_height {}", height);
+        }
+        header(
+            &mut out,
+            "mempool_transactions",
+            "gauge",
+            "Transactions not in a block.",
+        );
+        let _ = writeln!(out, "mempool_transactions {}", chain.mempool);
+
+        header(
+            &mut out,
+            "storage_operation_duration_seconds",
+            "histogram",
+            "How long storage took to answer.",
+        );
+        for (operation, histogram) in self.storage.lock().unwrap().iter() {
+            let labels = format!("operation=\"{}\",", operation);
+            histogram.write(&mut out, "storage_operation_duration_seconds", &labels);
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn method_label(method: &Method) -> &'static str {
+    METHODS
+        .into_iter()
+        .find(|m| *m == method.as_str())
+        .unwrap_or("other")
+}
+
+/// Storage that times how long each call takes.
+pub struct TimedStorage<S> {
+    inner: S,
+    metrics: Arc<Metrics>,
+}
+
+impl<S> TimedStorage<S> {
+    pub fn new(inner: S, metrics: Arc<Metrics>) -> Self {
+        TimedStorage { inner, metrics }
+    }
+
+    fn time<T>(&mut self, operation: &'static str, f: impl FnOnce(&mut S) -> T) -> T {
+        let start = Instant::now();
+        let res = f(&mut self.inner);
+        self.metrics.storage(operation, start.elapsed());
+        res
+    }
+}
+
+impl<S: Storage> Storage for TimedStorage<S> {
+    fn len(&mut self) -> Result<u64, String> {
+        self.time("len", |s| s.len())
+    }
+
+    fn range(&mut self, start: u64, end: u64) -> Result<Vec<String>, String> {
+        self.time("range", |s| s.range(start, end))
+    }
+
+    fn append(&mut self, lines: &[String]) -> Result<u64, String> {
+        self.time("append", |s| s.append(lines))
+    }
+
+    fn snapshot(&mut self) -> Result<Option<String>, String> {
+        self.time("snapshot", |s| s.snapshot())
+    }
+
+    fn save_snapshot(&mut self, snapshot: &str) -> Result<(), String> {
+        self.time("save_snapshot", |s| s.save_snapshot(snapshot))
+    }
+
+    fn audit_log(&mut self) -> Result<Vec<String>, String> {
+        self.time("audit_log", |s| s.audit_log())
+    }
+
+    fn append_audit_log(&mut self, entry: &str) -> Result<(), String> {
+        self.time("append_audit_log", |s| s.append_audit_log(entry))
+    }
+}
+
+#[cfg(test)]
+mod metrics_tests {
+    use std::time::Duration;
+
+    use hyper::{Method, StatusCode};
+
+    use super::{method_label, ChainStats, Metrics, Rejection};
+    use crate::test_util::{serve, tx};
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.request(&Method::GET, "/len", 200, Duration::from_millis(3));
+        metrics.request(&Method::GET, "/len", 200, Duration::from_millis(30));
+        let brew = Method::from_bytes(b"BREW").unwrap();
+        metrics.request(&brew, "/<id>", 400, Duration::from_millis(1));
+        metrics.rejected(Rejection::InvalidTransaction);
+        metrics.rejected(Rejection::InvalidTransaction);
+        let chain = ChainStats {
+            log_len: 5,
+            height: Some(2),
+            mempool: 1,
+        };
+        let out = metrics.render(&chain);
+
+        let labels = "method=\"GET\",route=\"/len\",status=\"200\"";
+        for line in [
+            format!("http_requests_total{{{}}} 2", labels),
+            format!(
+                "http_request_duration_seconds_bucket{{{},le=\"0.001\"}} 0",
+                labels
+            ),
+            format!(
+                "http_request_duration_seconds_bucket{{{},le=\"0.005\"}} 1",
+                labels
+            ),
+            format!(
+                "http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} 2",
+                labels
+            ),
+            format!("http_request_duration_seconds_count{{{}}} 2", labels),
+            "http_requests_total{method=\"other\",route=\"/<id>\",status=\"400\"} 1".to_string(),
+            "messages_rejected_total{reason=\"invalid_transaction\"} 2".to_string(),
+            "chain_height 2".to_string(),
+            "mempool_transactions 1".to_string(),
+        ] {
+            assert!(out.lines().any(|l| l == line), "missing {}", line);
+        }
+    }
+
+    #[test]
+    fn test_method_label() {
+        assert_eq!(method_label(&Method::PATCH), "PATCH");
+        for made_up in ["BREW", "get", "GET2"] {
+            let method = Method::from_bytes(made_up.as_bytes()).unwrap();
+            assert_eq!(method_label(&method), "other");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_served() {
+        let client = serve(0);
+        client.post(&tx("Zm9v")).await.unwrap();
+        assert!(client.post(&tx("Zm9v")).await.is_err());
+
+        let (status, out) = client
+            .send(Method::GET, "/metrics", String::new())
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::OK);
+        for line in [
+            "http_requests_total{method=\"POST\",route=\"/\",status=\"200\"} 1",
+            "http_requests_total{method=\"POST\",route=\"/\",status=\"400\"} 1",
+            "messages_accepted_total{kind=\"transaction\"} 1",
+            "messages_rejected_total{reason=\"duplicate\"} 1",
+            "log_length 2",
+            "mempool_transactions 1",
+            "storage_operation_duration_seconds_count{operation=\"append\"} 1",
+        ] {
+            assert!(out.lines().any(|l| l == line), "missing {}", line);
+        }
+    }
+}
@@ -0,0 +1,235 @@
+//! Mining blocks on top of a server's chain.
+//!
+//! A miner builds a block on the server's canonical tip out of its mempool,
+//! searches for a nonce that meets the next block's difficulty, and posts
+//! the block. Blocks with a Merkle root are hashed by their header alone, so
+//! trying a nonce doesn't mean hashing every transaction again.
+
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{
+    chain::unix_now,
+    client::Client,
+    hash::Hash,
+    messages::{NewBlock, NewMessage, Transaction},
+};
+
+/// The most transactions a block is given.
+const MAX_TRANSACTIONS: usize = 1000;
+
+/// How many nonces a thread tries between checks on whether to stop.
+const CHECK_EVERY: usize = 4096;
+
+/// A block on top of `prev` paying `miner_account`, with its nonce still to
+/// be found.
+pub fn assemble(
+    prev: Hash,
+    timestamp: u64,
+    transactions: Vec<Transaction>,
+    miner_account: &str,
+) -> NewBlock {
+    let mut block = NewBlock {
+        prev_hash: Some(prev),
+        timestamp: Some(timestamp),
+        merkle_root: None,
+        transactions,
+        nonce: 0,
+        miner_account: miner_account.to_string(),
+    };
+    block.merkle_root = Some(block.compute_merkle_root());
+    block
+}
+
+/// Tries nonces for `block` on `threads` threads until its hash has
+/// `difficulty` leading zero bits, returning it with that nonce, or `None`
+/// if `stop` is set first.
+pub fn search(
+    block: &NewBlock,
+    difficulty: u32,
+    threads: usize,
+    stop: &AtomicBool,
+) -> Option<NewBlock> {
+    let threads = threads.max(1);
+    let found = Mutex::new(None);
+    let done = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        for start in 0..threads as u64 {
+            let (found, done) = (&found, &done);
+            scope.spawn(move || {
+                let mut block = block.clone();
+                let should_stop = || done.load(Ordering::Relaxed) || stop.load(Ordering::Relaxed);
+                for (i, nonce) in (start..).step_by(threads).enumerate() {
+                    if i % CHECK_EVERY == 0 && should_stop() {
+                        return;
+                    }
+                    block.nonce = nonce;
+                    if block.hash().leading_zeros() >= difficulty {
+                        done.store(true, Ordering::Relaxed);
+                        found.lock().unwrap().get_or_insert(block);
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    found.into_inner().unwrap()
+}
+
+/// The transaction a block was turned down for, if that's why it was.
+fn rejected_transaction(error: &str) -> Option<u64> {
+    let rest = error.strip_prefix("Error: Transaction ")?;
+    rest.split(' ').next()?.parse().ok()
+}
+
+/// Mines blocks paying `account` on top of the chain of the server `client`
+/// talks to.
+pub struct Miner {
+    client: Client,
+    account: String,
+    threads: usize,
+    // how often to check whether the tip has moved on
+    poll_interval: Duration,
+    // transactions the server turned a block down for, left out from then on
+    rejected: HashSet<u64>,
+}
+
+impl Miner {
+    pub fn new(client: Client, account: String, threads: usize, poll_interval: Duration) -> Self {
+        Miner {
+            client,
+            account,
+            threads,
+            poll_interval,
+            rejected: HashSet::new(),
+        }
+    }
+
+    /// A block to mine on top of the current tip, and the difficulty it has
+    /// to meet. Transactions whose signatures don't verify are left out.
+    pub async fn template(&self) -> Result<(NewBlock, u32), String> {
+        let (serial, tip) = self.client.tip().await?;
+        let (_, difficulty) = self.client.difficulty().await?;
+
+        // after the tip, so after the median of the blocks before it too
+        let headers = self.client.headers_from(serial).await?;
+        let last = headers
+            .into_iter()
+            .find(|(s, _)| *s == serial)
+            .and_then(|(_, header)| format!("{}:", header).parse::<NewBlock>().ok())
+            .and_then(|block| block.timestamp);
+        let timestamp = last.map_or(unix_now(), |last| unix_now().max(last + 1));
+
+        let transactions = self
+            .client
+            .mempool()
+            .await?
+            .into_iter()
+            .filter(|t| !self.rejected.contains(&t.serial) && t.verify_signature().is_ok())
+            .take(MAX_TRANSACTIONS)
+            .collect();
+        Ok((
+            assemble(tip, timestamp, transactions, &self.account),
+            difficulty,
+        ))
+    }
+
+    /// Mines a block on top of the current tip and posts it, returning it,
+    /// or `None` if the tip moved on before a nonce was found.
+    pub async fn mine(&mut self) -> Result<Option<NewBlock>, String> {
+        let (block, difficulty) = self.template().await?;
+        let prev = block.prev_hash;
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut found = tokio::task::spawn_blocking({
+            let (stop, threads) = (stop.clone(), self.threads);
+            move || search(&block, difficulty, threads, &stop)
+        });
+
+        let found = loop {
+            tokio::select! {
+                found = &mut found => break found.map_err(|e| e.to_string())?,
+                _ = tokio::time::sleep(self.poll_interval) => {
+                    // a failed check isn't a reason to give up on the block
+                    if let Ok((_, tip)) = self.client.tip().await {
+                        if Some(tip) != prev {
+                            stop.store(true, Ordering::Relaxed);
+                        }
+                    }
+                }
+            }
+        };
+        let block = match found {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+
+        if let Err(e) = self.client.post(&NewMessage::NewBlock(block.clone())).await {
+            if let Some(serial) = rejected_transaction(&e) {
+                self.rejected.insert(serial);
+            }
+            return Err(e);
+        }
+        Ok(Some(block))
+    }
+}
+
+#[cfg(test)]
+mod miner_tests {
+    use std::{sync::atomic::AtomicBool, time::Duration};
+
+    use super::{assemble, search, Miner};
+    use crate::{
+        messages::{NewBlock, NewMessage},
+        test_util::{serve, KEY2},
+        wallet::Wallet,
+    };
+
+    #[test]
+    fn test_search() {
+        let block = assemble(NewBlock::genesis().hash(), 100, Vec::new(), KEY2);
+        let found = search(&block, 8, 4, &AtomicBool::new(false)).unwrap();
+        assert!(found.hash().leading_zeros() >= 8);
+        assert_eq!(found.merkle_root, block.merkle_root);
+
+        assert!(search(&block, 64, 4, &AtomicBool::new(true)).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_mines_on_server() {
+        let client = serve(8);
+        let wallet = Wallet::generate().unwrap();
+        let mut miner = Miner::new(
+            client.clone(),
+            wallet.account(),
+            2,
+            Duration::from_millis(50),
+        );
+
+        let first = miner.mine().await.unwrap().unwrap();
+        assert_eq!(client.tip().await.unwrap(), (1, first.hash()));
+
+        // the miner was paid for the first block, but this one has nothing
+        let broke = Wallet::generate().unwrap();
+        let pay = |w: &Wallet| {
+            let moves = vec![format!("{},1", KEY2).parse().unwrap()];
+            NewMessage::NewTransaction(w.transaction(moves, Default::default()))
+        };
+        client.post(&pay(&broke)).await.unwrap();
+        client.post(&pay(&wallet)).await.unwrap();
+
+        assert_eq!(
+            miner.mine().await.err().unwrap(),
+            "Error: Transaction 2 spends more than its sender has"
+        );
+        let second = miner.mine().await.unwrap().unwrap();
+        assert_eq!(second.transactions.len(), 1);
+        assert_eq!(second.transactions[0].serial, 3);
+        assert_eq!(client.tip().await.unwrap(), (4, second.hash()));
+    }
+}
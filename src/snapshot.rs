@@ -0,0 +1,305 @@
+//! Snapshots of the chain, so the server doesn't have to replay the whole
+//! log every time it starts.
+
+use std::{fmt::Display, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    chain::{ArchivedBlock, BlockInfo, Chain, Params},
+    hash::Hash,
+    ledger::Ledger,
+    storage::Storage,
+};
+
+/// How many messages are replayed at a time.
+const BATCH: u64 = 1000;
+
+/// The state of the chain as of the first `len` messages of the log, written
+/// as JSON.
+///
+/// Only the ledger and the latest blocks of the canonical branch are kept,
+/// along with the transactions not in a block yet, the Merkle leaves of the
+/// canonical blocks so their transactions can still be proven, and the
+/// hashes of all the messages so none is appended twice.
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub len: u64,
+    /// The hash of the last message covered, as stored, to tell whether the
+    /// log is still the one the snapshot was taken of.
+    pub last: Option<Hash>,
+    pub(crate) params: Params,
+    pub(crate) tip: Option<Hash>,
+    pub(crate) blocks: Vec<BlockInfo>,
+    pub(crate) ledger: Ledger,
+    // as posted, by serial
+    pub(crate) transactions: Vec<(u64, String)>,
+    // the canonical blocks with transactions, oldest first
+    #[serde(default)]
+    pub(crate) archive: Vec<ArchivedBlock>,
+    // sorted, and missing from snapshots taken before they were kept
+    #[serde(default)]
+    pub(crate) seen: Option<Vec<Hash>>,
+}
+
+impl Display for Snapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let json = serde_json::to_string(self).map_err(|_| std::fmt::Error)?;
+        write!(f, "{}", json)
+    }
+}
+
+impl FromStr for Snapshot {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str(s).map_err(|e| format!("Snapshot is unreadable: {}", e))
+    }
+}
+
+/// The hash of the message at `serial`, as stored.
+fn message_hash(storage: &mut dyn Storage, serial: u64) -> Result<Option<Hash>, String> {
+    let line = storage.range(serial, serial + 1)?;
+    Ok(line.first().map(|line| Hash::of(line.as_bytes())))
+}
+
+/// Picks the chain up from the latest snapshot in `storage` if it's still
+/// usable, then replays the messages after it.
+pub fn load_chain(storage: &mut dyn Storage, params: Params) -> Result<Chain, String> {
+    let len = storage.len()?;
+    let chain = match storage.snapshot()? {
+        Some(snapshot) => restore(storage, params.clone(), &snapshot),
+        None => Err("There is no snapshot".to_string()),
+    };
+    let mut chain = chain.unwrap_or_else(|e| {
+        info!(reason = %e, "Replaying the whole log");
+        Chain::new(params)
+    });
+
+    let mut start = chain.log_len();
+    while start < len {
+        let end = (start + BATCH).min(len);
+        let lines = storage.range(start, end)?;
+        chain.extend(lines.iter().map(String::as_str));
+        start = end;
+    }
+    Ok(chain)
+}
+
+fn restore(storage: &mut dyn Storage, params: Params, snapshot: &str) -> Result<Chain, String> {
+    let snapshot = snapshot.parse::<Snapshot>()?;
+    let last = match snapshot.len {
+        0 => None,
+        len => message_hash(storage, len - 1)?,
+    };
+    if last != snapshot.last {
+        return Err("Snapshot is of a different log".to_string());
+    }
+    Chain::from_snapshot(params, snapshot)
+}
+
+/// Saves a snapshot of `chain` as the latest one in `storage`, which has to
+/// hold the log the chain was built from.
+pub fn save(storage: &mut dyn Storage, chain: &Chain) -> Result<(), String> {
+    let mut snapshot = chain.snapshot();
+    if snapshot.len != 0 {
+        snapshot.last = message_hash(storage, snapshot.len - 1)?;
+    }
+    storage.save_snapshot(&snapshot.to_string())
+}
+
+#[cfg(test)]
+mod snapshot_tests {
+    use super::{load_chain, save, Snapshot};
+    use crate::{
+        chain::{Chain, Params},
+        difficulty::DifficultySchedule,
+        hash::Hash,
+        messages::{NewBlock, NewMessage},
+        storage::{Memory, Storage},
+        test_util::{KEY, KEY2, SIG},
+    };
+
+    fn params() -> Params {
+        Params {
+            difficulty: DifficultySchedule {
+                initial: 0,
+                retarget_interval: 2,
+                target_block_time: 60,
+            },
+            ..Params::default()
+        }
+    }
+
+    /// Appends a block on top of `prev` to the log, returning its hash.
+    fn post_block(log: &mut Memory, prev: Hash, timestamp: u64, txs: &str) -> Hash {
+        let chain = Chain::replay(params(), log.messages.iter().map(String::as_str));
+        let difficulty = chain.next_difficulty(chain.get(&prev));
+        let mut block = format!("block:v3:{}:{}:0:{}:{}", prev, timestamp, KEY2, txs)
+            .parse::<NewBlock>()
+            .unwrap();
+        block.merkle_root = Some(block.compute_merkle_root());
+        while block.hash().leading_zeros() < difficulty {
+            block.nonce += 1;
+        }
+        log.append(&[block.to_string()]).unwrap();
+        block.hash()
+    }
+
+    fn tx(unique: &str) -> String {
+        format!("transaction:{}:{}:{}:{},1", unique, SIG, KEY, KEY2)
+    }
+
+    fn assert_same(a: &Chain, b: &Chain) {
+        assert_eq!(a.tip_info(), b.tip_info());
+        assert_eq!(a.log_len(), b.log_len());
+        assert_eq!(a.ledger().balances(), b.ledger().balances());
+        assert_eq!(a.difficulty(), b.difficulty());
+        let mempool = |c: &Chain| {
+            c.mempool()
+                .iter()
+                .map(|(serial, t)| (*serial, t.to_string()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(mempool(a), mempool(b));
+    }
+
+    #[test]
+    fn test_picks_up_after_snapshot() {
+        let mut log = Memory::new(vec![
+            NewBlock::genesis().to_string(),
+            tx("Zm9v"),
+            tx("YmFy"),
+        ]);
+        let mut prev = NewBlock::genesis().hash();
+        prev = post_block(
+            &mut log,
+            prev,
+            100,
+            &format!("1;{}", tx("Zm9v").replace(':', ";")),
+        );
+        prev = post_block(&mut log, prev, 110, "");
+
+        let chain = load_chain(&mut log, params()).unwrap();
+        save(&mut log, &chain).unwrap();
+        let snapshot = log.snapshot.clone().unwrap().parse::<Snapshot>().unwrap();
+        assert_eq!(snapshot.len, 5);
+        assert_eq!(snapshot.transactions, vec![(2, tx("YmFy"))]);
+
+        // messages after the snapshot are replayed, readable or not
+        log.messages.push("garbage".to_string());
+        prev = post_block(
+            &mut log,
+            prev,
+            120,
+            &format!("2;{}", tx("YmFy").replace(':', ";")),
+        );
+        post_block(&mut log, prev, 130, "");
+        log.append(&[tx("YmF6")]).unwrap();
+
+        let replayed = Chain::replay(params(), log.messages.iter().map(String::as_str));
+        let restored = load_chain(&mut log, params()).unwrap();
+        assert_same(&restored, &replayed);
+        assert_eq!(restored.ledger().balance(KEY2).to_string(), "202");
+
+        // it still knows every message, from before the snapshot or after
+        for line in log.messages.iter().filter(|line| *line != "garbage") {
+            let hash = line.parse::<NewMessage>().unwrap().hash();
+            assert!(restored.has_seen(&hash));
+        }
+    }
+
+    #[test]
+    fn test_proves_inclusion_after_snapshot() {
+        let mut log = Memory::new(vec![
+            NewBlock::genesis().to_string(),
+            tx("Zm9v"),
+            tx("YmFy"),
+            tx("YmF6"),
+        ]);
+        let embed = |serial: u64| {
+            let line = log.messages[serial as usize].replace(':', ";");
+            format!("{};{}", serial, line)
+        };
+        let txs = format!("{}:{}", embed(1), embed(2));
+        let last = embed(3);
+        let prev = post_block(&mut log, NewBlock::genesis().hash(), 100, &txs);
+        let proof = |chain: &Chain, serial| {
+            chain
+                .proof(serial)
+                .map(|(info, header, steps)| (*info, header, steps))
+        };
+
+        // what's proven before a snapshot is still proven from it
+        let chain = load_chain(&mut log, params()).unwrap();
+        save(&mut log, &chain).unwrap();
+        let restored = load_chain(&mut log, params()).unwrap();
+        for serial in 1..=2 {
+            assert!(proof(&chain, serial).is_ok());
+            assert_eq!(proof(&restored, serial), proof(&chain, serial));
+        }
+        assert!(proof(&restored, 3).is_err());
+
+        // and from the snapshots after it
+        post_block(&mut log, prev, 110, &last);
+        let restored = load_chain(&mut log, params()).unwrap();
+        save(&mut log, &restored).unwrap();
+        let replayed = Chain::replay(params(), log.messages.iter().map(String::as_str));
+        let restored = load_chain(&mut log, params()).unwrap();
+        for serial in 1..=3 {
+            assert!(proof(&replayed, serial).is_ok());
+            assert_eq!(proof(&restored, serial), proof(&replayed, serial));
+        }
+    }
+
+    #[test]
+    fn test_ignores_stale_snapshots() {
+        let mut log = Memory::new(vec![NewBlock::genesis().to_string(), tx("Zm9v")]);
+        let chain = load_chain(&mut log, params()).unwrap();
+        save(&mut log, &chain).unwrap();
+
+        // the log was replaced by another one
+        let mut other = Memory::new(vec![NewBlock::genesis().to_string(), tx("YmFy")]);
+        other.snapshot = log.snapshot.clone();
+        let restored = load_chain(&mut other, params()).unwrap();
+        assert_eq!(restored.mempool()[0].1.to_string(), tx("YmFy"));
+
+        // or the rules changed
+        let restored = load_chain(&mut log, Params::default()).unwrap();
+        let replayed = Chain::replay(Params::default(), log.messages.iter().map(String::as_str));
+        assert_same(&restored, &replayed);
+
+        // or it was taken before the hashes of the messages were kept
+        let mut snapshot = log.snapshot.clone().unwrap().parse::<Snapshot>().unwrap();
+        snapshot.seen = None;
+        log.snapshot = Some(snapshot.to_string());
+        let restored = load_chain(&mut log, params()).unwrap();
+        let hash = tx("Zm9v").parse::<NewMessage>().unwrap().hash();
+        assert!(restored.has_seen(&hash));
+    }
+
+    #[test]
+    fn test_blocks_cant_fork_off_before_snapshot() {
+        let mut log = Memory::new(vec![NewBlock::genesis().to_string()]);
+        let root = NewBlock::genesis().hash();
+        let a = post_block(&mut log, root, 100, "");
+        let chain = load_chain(&mut log, params()).unwrap();
+        save(&mut log, &chain).unwrap();
+
+        let restored = load_chain(&mut log, params()).unwrap();
+        let fork = format!("block:v4:{}:101:{}:0:{}:", root, Hash::ZERO, KEY2)
+            .parse::<NewMessage>()
+            .unwrap();
+        assert!(chain.check(&fork).is_ok());
+        assert_eq!(
+            restored.check(&fork).err().unwrap(),
+            "Block forks off before the latest snapshot"
+        );
+
+        let next = format!("block:v4:{}:101:{}:0:{}:", a, Hash::ZERO, KEY2)
+            .parse::<NewMessage>()
+            .unwrap();
+        assert!(restored.check(&next).is_ok());
+    }
+}
@@ -0,0 +1,132 @@
+//! Where the message log is kept.
+//!
+//! The log is a list of posted lines, where a message's serial is its index.
+//! The server keeps it in redis, but anything that can append lines and read
+//! them back by index can hold a copy. Next to the log, storage keeps the
+//! latest snapshot of the chain so it doesn't have to be replayed in full,
+//! and the audit log of what was done through admin routes.
+
+use redis::Commands;
+
+/// The redis list the log is stored in.
+pub const MESSAGES_KEY: &str = "messages";
+
+/// The redis key the latest snapshot is stored under.
+pub const SNAPSHOT_KEY: &str = "snapshot";
+
+/// The redis list the audit log is stored in.
+pub const AUDIT_KEY: &str = "audit";
+
+pub trait Storage {
+    /// The number of messages in the log.
+    fn len(&mut self) -> Result<u64, String>;
+
+    fn is_empty(&mut self) -> Result<bool, String> {
+        Ok(self.len()? == 0)
+    }
+
+    /// The messages with serials from `start` up to but not including `end`.
+    fn range(&mut self, start: u64, end: u64) -> Result<Vec<String>, String>;
+
+    /// Appends `lines` to the log in one go, returning its new length.
+    fn append(&mut self, lines: &[String]) -> Result<u64, String>;
+
+    fn snapshot(&mut self) -> Result<Option<String>, String>;
+
+    /// Replaces the latest snapshot.
+    fn save_snapshot(&mut self, snapshot: &str) -> Result<(), String>;
+
+    /// The entries of the audit log, oldest first, see [`crate::bans`].
+    fn audit_log(&mut self) -> Result<Vec<String>, String>;
+
+    fn append_audit_log(&mut self, entry: &str) -> Result<(), String>;
+}
+
+impl Storage for redis::Connection {
+    fn len(&mut self) -> Result<u64, String> {
+        self.llen(MESSAGES_KEY).map_err(|e| e.to_string())
+    }
+
+    fn range(&mut self, start: u64, end: u64) -> Result<Vec<String>, String> {
+        if end <= start {
+            return Ok(Vec::new());
+        }
+        self.lrange(MESSAGES_KEY, start as isize, end as isize - 1)
+            .map_err(|e| e.to_string())
+    }
+
+    fn append(&mut self, lines: &[String]) -> Result<u64, String> {
+        if lines.is_empty() {
+            return self.len();
+        }
+        self.rpush(MESSAGES_KEY, lines).map_err(|e| e.to_string())
+    }
+
+    fn snapshot(&mut self) -> Result<Option<String>, String> {
+        self.get(SNAPSHOT_KEY).map_err(|e| e.to_string())
+    }
+
+    fn save_snapshot(&mut self, snapshot: &str) -> Result<(), String> {
+        self.set(SNAPSHOT_KEY, snapshot).map_err(|e| e.to_string())
+    }
+
+    fn audit_log(&mut self) -> Result<Vec<String>, String> {
+        self.lrange(AUDIT_KEY, 0, -1).map_err(|e| e.to_string())
+    }
+
+    fn append_audit_log(&mut self, entry: &str) -> Result<(), String> {
+        self.rpush(AUDIT_KEY, entry).map_err(|e| e.to_string())
+    }
+}
+
+/// A log kept in memory, for tests and throwaway copies.
+#[derive(Clone, Debug, Default)]
+pub struct Memory {
+    pub messages: Vec<String>,
+    pub snapshot: Option<String>,
+    pub audit: Vec<String>,
+}
+
+impl Memory {
+    pub fn new(messages: Vec<String>) -> Self {
+        Memory {
+            messages,
+            ..Memory::default()
+        }
+    }
+}
+
+impl Storage for Memory {
+    fn len(&mut self) -> Result<u64, String> {
+        Ok(self.messages.len() as u64)
+    }
+
+    fn range(&mut self, start: u64, end: u64) -> Result<Vec<String>, String> {
+        let end = (end as usize).min(self.messages.len());
+        let start = (start as usize).min(end);
+        Ok(self.messages[start..end].to_vec())
+    }
+
+    fn append(&mut self, lines: &[String]) -> Result<u64, String> {
+        self.messages.extend_from_slice(lines);
+        Ok(self.messages.len() as u64)
+    }
+
+    fn snapshot(&mut self) -> Result<Option<String>, String> {
+        Ok(self.snapshot.clone())
+    }
+
+    fn save_snapshot(&mut self, snapshot: &str) -> Result<(), String> {
+        self.snapshot = Some(snapshot.to_string());
+        Ok(())
+    }
+
+    fn audit_log(&mut self) -> Result<Vec<String>, String> {
+        Ok(self.audit.clone())
+    }
+
+    fn append_audit_log(&mut self, entry: &str) -> Result<(), String> {
+        self.audit.push(entry.to_string());
+        Ok(())
+    }
+}
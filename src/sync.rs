@@ -0,0 +1,186 @@
+//! Copying another server's log into storage.
+//!
+//! Pages of the log are downloaded several at a time, but appended in order,
+//! so a sync that's cut short leaves a log the next one picks up from.
+
+use futures::StreamExt;
+
+use crate::{
+    chain::{Chain, Params},
+    client::Client,
+    storage::Storage,
+    verify,
+};
+
+/// How many messages are asked for at a time, which is at most what the
+/// server sends back for a single `GET /<id>`.
+const PAGE: u64 = 200;
+
+/// Copies the messages the server `client` talks to has past the end of
+/// `storage`, downloading up to `connections` pages at once, and calling
+/// `progress` with the number copied so far and the total after every page.
+/// Returns the number copied.
+///
+/// The log in `storage` has to be the start of the server's. Unless `params`
+/// is `None`, messages have to keep to the rules given the ones before them,
+/// like [`verify`] would check them.
+pub async fn sync(
+    storage: &mut dyn Storage,
+    client: &Client,
+    params: Option<Params>,
+    connections: usize,
+    progress: &mut dyn FnMut(u64, u64),
+) -> Result<u64, String> {
+    let start = storage.len()?;
+    if start > 0 {
+        let ours = storage.range(start - 1, start)?;
+        let theirs = client.lines_from(start - 1).await?;
+        if theirs.first().map(|(_, line)| line) != ours.first() {
+            return Err("Log isn't the start of the server's".to_string());
+        }
+    }
+
+    let mut chain = match params {
+        Some(params) => {
+            let stored = storage.range(0, start)?;
+            Some(verify::verify(params, stored.iter().map(String::as_str)).0)
+        }
+        None => None,
+    };
+
+    // go around again for whatever was posted in the meantime
+    let mut next = start;
+    loop {
+        let len = client.log_len().await?;
+        if len <= next {
+            break;
+        }
+        let mut pages = futures::stream::iter((next..len).step_by(PAGE as usize))
+            .map(|from| fetch(client, from, (from + PAGE).min(len)))
+            .buffered(connections.max(1));
+        while let Some(page) = pages.next().await {
+            let lines = page?;
+            if let Some(chain) = &mut chain {
+                check_rules(chain, next, &lines)?;
+            }
+            next = storage.append(&lines)?;
+            progress(next - start, len - start);
+        }
+    }
+    Ok(next - start)
+}
+
+/// The messages with serials from `from` up to but not including `to`.
+async fn fetch(client: &Client, from: u64, to: u64) -> Result<Vec<String>, String> {
+    let mut page = client.lines_from(from).await?;
+    page.truncate((to - from) as usize);
+    if page.len() as u64 != to - from {
+        return Err(format!("Server is missing messages from {} on", from));
+    }
+    page.into_iter()
+        .zip(from..)
+        .map(|((serial, line), expected)| match serial == expected {
+            true => Ok(line),
+            false => Err(format!("Server sent serial {} for {}", serial, expected)),
+        })
+        .collect()
+}
+
+fn check_rules(chain: &mut Chain, start: u64, lines: &[String]) -> Result<(), String> {
+    for (serial, line) in (start..).zip(lines) {
+        let message = verify::check_entry(chain, serial, line)
+            .map_err(|e| format!("Message {} is invalid: {}", serial, e))?;
+        chain.push(&message, serial);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod sync_tests {
+    use super::sync;
+    use crate::{
+        chain::Params,
+        difficulty::DifficultySchedule,
+        messages::NewBlock,
+        storage::Memory,
+        test_util::{serve_log, KEY2},
+        wallet::Wallet,
+    };
+
+    fn params() -> Params {
+        Params {
+            difficulty: DifficultySchedule {
+                initial: 0,
+                ..DifficultySchedule::default()
+            },
+            ..Params::default()
+        }
+    }
+
+    /// The genesis block followed by `n` signed transactions.
+    fn log(n: usize) -> Vec<String> {
+        let wallet = Wallet::generate().unwrap();
+        let moves = || vec![format!("{},1", KEY2).parse().unwrap()];
+        let mut log = vec![NewBlock::genesis().to_string()];
+        log.extend((0..n).map(|_| wallet.transaction(moves(), Default::default()).to_string()));
+        log
+    }
+
+    #[tokio::test]
+    async fn test_copies_the_log() {
+        let source = log(450);
+        let client = serve_log(source.clone(), 0);
+
+        let mut dest = Memory::new(source[..1].to_vec());
+        let mut seen = Vec::new();
+        let copied = sync(&mut dest, &client, Some(params()), 3, &mut |done, total| {
+            seen.push((done, total))
+        })
+        .await
+        .unwrap();
+        assert_eq!(copied, 450);
+        assert_eq!(seen, vec![(200, 450), (400, 450), (450, 450)]);
+        assert_eq!(dest.messages, source);
+
+        // there's nothing left to copy
+        let copied = sync(&mut dest, &client, Some(params()), 3, &mut |_, _| {}).await;
+        assert_eq!(copied, Ok(0));
+    }
+
+    #[tokio::test]
+    async fn test_resumes() {
+        let source = log(250);
+        let client = serve_log(source.clone(), 0);
+
+        let mut dest = Memory::new(source[..120].to_vec());
+        let copied = sync(&mut dest, &client, Some(params()), 2, &mut |_, _| {}).await;
+        assert_eq!(copied, Ok(131));
+        assert_eq!(dest.messages, source);
+
+        // but only a log the server's starts with
+        let mut other = Memory::new(log(1));
+        assert_eq!(
+            sync(&mut other, &client, None, 2, &mut |_, _| {}).await,
+            Err("Log isn't the start of the server's".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_checks_the_rules() {
+        let mut source = log(2);
+        source.insert(2, "garbage".to_string());
+        let client = serve_log(source.clone(), 0);
+
+        let mut dest = Memory::new(source[..1].to_vec());
+        assert_eq!(
+            sync(&mut dest, &client, Some(params()), 2, &mut |_, _| {}).await,
+            Err("Message 2 is invalid: Message has less than two parts".to_string())
+        );
+        assert_eq!(dest.messages, source[..1]);
+
+        // unless told not to
+        let copied = sync(&mut dest, &client, None, 2, &mut |_, _| {}).await;
+        assert_eq!(copied, Ok(3));
+        assert_eq!(dest.messages, source);
+    }
+}
@@ -0,0 +1,127 @@
+//! Fixtures shared by the unit tests.
+
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+
+use crate::{
+    chain::Params,
+    client::Client,
+    difficulty::DifficultySchedule,
+    http::HTTP,
+    messages::{NewBlock, NewMessage, NewTransaction},
+    storage::{Memory, Storage},
+    wallet::Wallet,
+};
+
+/// The genesis miner's key, a well-formed 512-bit RSA ssh key.
+pub const KEY: &str = concat!(
+    "AAAAB3NzaC1yc2EAAAADAQABAAAAQQDbXz4rfbrRrXYQJbwuC",
+    "kIyIsccHRpxhxqxgKeneVF4eUXof6e2nLvdXkGA0Y6uBAQ6N7qKxasVTR/2s1N2OBWF"
+);
+
+pub const KEY2: &str = concat!(
+    "AAAAB3NzaC1yc2EAAAADAQABAAAAQQC+1f2dvLs4dR0mIPiS6",
+    "qDbZMs0BUrb0Sb5bPw8cfMowmcwrnTdsZ/Yjxk9u6pOZxWdc6S1eIRv7DyHpMKY/9hl"
+);
+
+/// A signature of the right length that doesn't verify against anything.
+pub const SIG: &str = concat!(
+    "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA",
+    "AAAAAAAAAAAAAA=="
+);
+
+/// The wallet fixtures are signed with, the same one for the whole run.
+pub fn wallet() -> &'static Wallet {
+    static WALLET: OnceLock<Wallet> = OnceLock::new();
+    WALLET.get_or_init(|| Wallet::generate().unwrap())
+}
+
+/// A transaction from [`wallet`] paying 1 to KEY2, where the same `unique`
+/// string always makes the same transaction.
+pub fn tx(unique: &str) -> NewMessage {
+    pay(unique, &format!("{},1", KEY2))
+}
+
+/// A transaction from [`wallet`] with `unique` as its unique string, paying
+/// out `rest`, its moves and fee as they're written in a message.
+pub fn pay(unique: &str, rest: &str) -> NewMessage {
+    let mut t = format!(
+        "transaction:{}:{}:{}:{}",
+        unique,
+        SIG,
+        wallet().account(),
+        rest
+    )
+    .parse::<NewTransaction>()
+    .unwrap();
+    wallet().sign(&mut t);
+    NewMessage::NewTransaction(t)
+}
+
+/// Starts a server on a free port with only the genesis block, where blocks
+/// need `difficulty` bits to begin with, returning a client for it.
+pub fn serve(difficulty: u32) -> Client {
+    serve_log(vec![NewBlock::genesis().to_string()], difficulty)
+}
+
+/// Like [`serve`], but for a server with `log`.
+pub fn serve_log(log: Vec<String>, difficulty: u32) -> Client {
+    let params = Params {
+        difficulty: DifficultySchedule {
+            initial: difficulty,
+            ..DifficultySchedule::default()
+        },
+        ..Params::default()
+    };
+    let http = HTTP::new(
+        "127.0.0.1".to_string(),
+        "0".to_string(),
+        params,
+        None,
+        vec![],
+        None,
+        vec![],
+    );
+    let (addr, server) = http.bind(Memory::new(log)).unwrap();
+    tokio::spawn(server);
+    Client::new(&format!("http://{}", addr))
+}
+
+/// Storage in memory that a test can look at while a server has it.
+#[derive(Clone, Default)]
+pub struct Shared(Arc<Mutex<Memory>>);
+
+impl Shared {
+    pub fn lock(&self) -> MutexGuard<'_, Memory> {
+        self.0.lock().unwrap()
+    }
+}
+
+impl Storage for Shared {
+    fn len(&mut self) -> Result<u64, String> {
+        self.lock().len()
+    }
+
+    fn range(&mut self, start: u64, end: u64) -> Result<Vec<String>, String> {
+        self.lock().range(start, end)
+    }
+
+    fn append(&mut self, lines: &[String]) -> Result<u64, String> {
+        self.lock().append(lines)
+    }
+
+    fn snapshot(&mut self) -> Result<Option<String>, String> {
+        self.lock().snapshot()
+    }
+
+    fn save_snapshot(&mut self, snapshot: &str) -> Result<(), String> {
+        self.lock().save_snapshot(snapshot)
+    }
+
+    fn audit_log(&mut self) -> Result<Vec<String>, String> {
+        self.lock().audit_log()
+    }
+
+    fn append_audit_log(&mut self, entry: &str) -> Result<(), String> {
+        self.lock().append_audit_log(entry)
+    }
+}
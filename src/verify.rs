@@ -0,0 +1,128 @@
+//! Auditing a log that may have been written before the rules existed.
+
+use std::fmt::Display;
+
+use crate::{
+    chain::{Chain, Params},
+    messages::{Message, NewMessage},
+};
+
+/// An entry in the log that breaks the rules, and why.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Invalid {
+    pub serial: u64,
+    pub reason: String,
+}
+
+impl Display for Invalid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.serial, self.reason)
+    }
+}
+
+/// Checks the entry at `serial` against `chain`, the chain of the valid
+/// entries before it.
+pub fn check_entry(chain: &Chain, serial: u64, line: &str) -> Result<NewMessage, String> {
+    let message = audit_entry(chain, serial, line)?;
+    if let NewMessage::NewTransaction(t) = &message {
+        t.verify_signature()?;
+    }
+    Ok(message)
+}
+
+/// Like [`check_entry`], but leaves out signatures, which the server doesn't
+/// check on posts either.
+pub fn audit_entry(chain: &Chain, serial: u64, line: &str) -> Result<NewMessage, String> {
+    let message = NewMessage::parse_lenient(line).map_err(|e| {
+        // entries are served under their index, so one stored with its own
+        // serial would end up with two
+        match Message::parse_lenient(line) {
+            Ok(m) if m.serial() == serial => "Entry is stored with its serial".to_string(),
+            Ok(m) => format!("Entry is stored with serial {}", m.serial()),
+            Err(_) => e,
+        }
+    })?;
+    chain.audit(&message)?;
+    Ok(message)
+}
+
+/// Replays `log`, checking every entry like a newly posted message, and
+/// returns the entries that break the rules along with the chain of the
+/// ones that don't. Invalid entries are left out of the chain, so anything
+/// relying on them is reported as well.
+pub fn verify<'a>(params: Params, log: impl IntoIterator<Item = &'a str>) -> (Chain, Vec<Invalid>) {
+    let mut chain = Chain::new(params);
+    let mut invalid = Vec::new();
+    for (serial, line) in log.into_iter().enumerate() {
+        let serial = serial as u64;
+        match check_entry(&chain, serial, line) {
+            Ok(message) => chain.push(&message, serial),
+            Err(reason) => invalid.push(Invalid { serial, reason }),
+        }
+    }
+    (chain, invalid)
+}
+
+#[cfg(test)]
+mod verify_tests {
+    use rsa::{rand_core::OsRng, RsaPrivateKey};
+
+    use super::{verify, Invalid};
+    use crate::{
+        chain::Params,
+        difficulty::DifficultySchedule,
+        keys,
+        messages::{NewBlock, NewTransaction},
+        test_util::{KEY2, SIG},
+    };
+
+    #[test]
+    fn test_reports_invalid_entries() {
+        let private = RsaPrivateKey::new(&mut OsRng, 512).unwrap();
+        let miner = keys::encode_public_key(&private.to_public_key());
+        let unsigned = format!("transaction:Zm9v:{}:{}:{},20", SIG, miner, KEY2)
+            .parse::<NewTransaction>()
+            .unwrap();
+        let signed = unsigned
+            .to_string()
+            .replace(SIG, &keys::sign(&private, &unsigned.signing_bytes()));
+        let embed = |serial: u64, t: &str| format!("{};{}", serial, t.replace(':', ";"));
+
+        let log = [
+            NewBlock::genesis().to_string(),
+            format!("block:1:{}:", miner),
+            signed.clone(),
+            unsigned.to_string(),
+            format!("block:2:{}:{}", KEY2, embed(3, &unsigned.to_string())),
+            format!("5:{}", signed),
+            format!("block:3:{}:{}", KEY2, embed(2, &signed)),
+            "garbage".to_string(),
+            format!("block:4:{}:{}", KEY2, embed(2, &signed)),
+        ];
+        let params = Params {
+            difficulty: DifficultySchedule {
+                initial: 0,
+                ..DifficultySchedule::default()
+            },
+            ..Params::default()
+        };
+        let (chain, invalid) = verify(params, log.iter().map(String::as_str));
+
+        let reason = |serial: u64, reason: &str| Invalid {
+            serial,
+            reason: reason.to_string(),
+        };
+        assert_eq!(
+            invalid,
+            vec![
+                reason(3, "Signature doesn't match the sender's key"),
+                reason(4, "Transaction 3 is not in the log"),
+                reason(5, "Entry is stored with its serial"),
+                reason(7, "Message has less than two parts"),
+                reason(8, "Transaction 2 is already in a block"),
+            ]
+        );
+        assert_eq!(chain.tip_info().unwrap().serial, 6);
+        assert_eq!(chain.ledger().balance(&miner).to_string(), "30");
+    }
+}
@@ -0,0 +1,121 @@
+//! An account's private key and the transactions it signs.
+//!
+//! Keys are kept as PKCS#1 PEM files, and the account they sign for is the
+//! 512-bit ssh-rsa public key the server expects, see [`crate::keys`].
+
+use std::str::FromStr;
+
+use rsa::{
+    pkcs1::{DecodeRsaPrivateKey, EncodeRsaPrivateKey, LineEnding},
+    rand_core::{OsRng, RngCore},
+    traits::PublicKeyParts,
+    RsaPrivateKey,
+};
+
+use crate::{
+    amount::Amount,
+    keys,
+    messages::{Move, NewTransaction},
+};
+
+/// The size of the keys accounts are named by, which is the only one whose
+/// encoding has the length the server accepts.
+const KEY_BITS: usize = 512;
+
+/// The number of random bytes in a transaction's unique string.
+const UNIQUE_BYTES: usize = 12;
+
+pub struct Wallet {
+    key: RsaPrivateKey,
+}
+
+impl Wallet {
+    pub fn generate() -> Result<Self, String> {
+        let key = RsaPrivateKey::new(&mut OsRng, KEY_BITS).map_err(|e| e.to_string())?;
+        Ok(Wallet { key })
+    }
+
+    /// The public key coins are sent to, as it's written in messages.
+    pub fn account(&self) -> String {
+        keys::encode_public_key(&self.key.to_public_key())
+    }
+
+    pub fn to_pem(&self) -> Result<String, String> {
+        self.key
+            .to_pkcs1_pem(LineEnding::LF)
+            .map(|pem| pem.to_string())
+            .map_err(|e| e.to_string())
+    }
+
+    /// A signed transaction paying out `moves` plus `fee`, with a fresh
+    /// unique string.
+    pub fn transaction(&self, moves: Vec<Move>, fee: Amount) -> NewTransaction {
+        let mut unique = [0u8; UNIQUE_BYTES];
+        OsRng.fill_bytes(&mut unique);
+        let mut transaction = NewTransaction {
+            unique_string: base64::encode(unique),
+            sig: String::new(),
+            sender: self.account(),
+            moves,
+            fee,
+        };
+        self.sign(&mut transaction);
+        transaction
+    }
+
+    /// Signs `transaction` in place, which only verifies if this wallet's
+    /// account is its sender.
+    pub fn sign(&self, transaction: &mut NewTransaction) {
+        transaction.sig = keys::sign(&self.key, &transaction.signing_bytes());
+    }
+}
+
+impl FromStr for Wallet {
+    type Err = String;
+
+    /// Reads a key from a PKCS#1 PEM file's contents.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let key = RsaPrivateKey::from_pkcs1_pem(s).map_err(|_| "Key is not a PEM RSA key")?;
+        if key.n().bits() != KEY_BITS {
+            return Err(format!("Key is not a {}-bit key", KEY_BITS));
+        }
+        Ok(Wallet { key })
+    }
+}
+
+#[cfg(test)]
+mod wallet_tests {
+    use super::Wallet;
+    use crate::{
+        messages::{NewMessage, NewTransaction},
+        test_util::KEY2,
+    };
+
+    #[test]
+    fn test_key_round_trip() {
+        let wallet = Wallet::generate().unwrap();
+        assert_eq!(wallet.account().len(), 116);
+
+        let pem = wallet.to_pem().unwrap();
+        let read = pem.parse::<Wallet>().unwrap();
+        assert_eq!(read.account(), wallet.account());
+        assert!("garbage".parse::<Wallet>().is_err());
+    }
+
+    #[test]
+    fn test_signs_transactions() {
+        let wallet = Wallet::generate().unwrap();
+        let moves = vec![format!("{},2.5", KEY2).parse().unwrap()];
+        let transaction = wallet.transaction(moves, "0.1".parse().unwrap());
+        assert!(transaction.verify_signature().is_ok());
+
+        // and it makes it through the parser as it is
+        let line = transaction.to_string();
+        let parsed = line.parse::<NewTransaction>().unwrap();
+        assert!(parsed.verify_signature().is_ok());
+        assert!(line.parse::<NewMessage>().is_ok());
+
+        let other = wallet.transaction(Vec::new(), "0.1".parse().unwrap());
+        assert_ne!(other.unique_string, transaction.unique_string);
+    }
+}